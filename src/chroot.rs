@@ -1,86 +1,281 @@
+use crate::error::{InstallError, Result, StepContext};
+use crate::plan::{
+    check_password, is_valid_platform, is_valid_username, KeySource, Plan, RootAccount, UserPlan,
+};
+use crate::runner::{execute_steps, Cmd, Runner};
 use std::io::{self, Write};
-use std::process::Command;
-use std::thread;
-use std::time::Duration;
-
-pub fn chroot() {
-    // Prompt the user for a username and password
-    print!("Enter username: ");
-    io::stdout().flush();
-    let mut username = String::new();
-    io::stdin().read_line(&mut username);
-
-    print!("Enter password: ");
-    io::stdout().flush();
-    let mut password = String::new();
-    io::stdin().read_line(&mut password);
-
-    print!("Enter your Platform in Lower Case(intel/amd): ");
-    io::stdout().flush();
-    let mut platform = String::new();
-    io::stdin().read_line(&mut platform);
+
+// The key the archzfs repository signs its packages with.
+const ARCHZFS_KEY: &str = "DDF7DB817396A49B2A2723F7403BD972F75D9D76";
+
+pub fn chroot(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    let answers = chroot_answers(plan)?;
+    let platform = answers.system.platform.clone().unwrap_or_default();
 
     // Call the chroot_install() function to install packages and configure the ZFS filesystem
-    chroot_install(username.trim(), password.trim(), platform.trim());
+    chroot_install(&answers, runner, &answers.users, &platform).during("Installing the chroot")?;
 
-    // Exit the program
-    std::process::exit(0);
+    Ok(())
 }
 
-// This function installs packages and configures the ZFS filesystem in a chroot environment by executing a sequence of shell commands using `Command` from the standard library. The commands add a repository, install packages, create a user, set up a cache file, configure the bootloader, enable services, and generate an initramfs. The function takes a username and password as input and returns a `String` indicating the completion of the operation.
-
-pub fn chroot_install(username: &str, password: &str, platform: &str) -> std::io::Result<String> {
-    // Define a vector of shell commands to execute
-    let commands = vec![
-        "echo -e '[archzfs]\nServer = https://archzfs.com/$repo/$arch' >>/etc/pacman.conf".to_string(), // Add a repository
-        "pacman-key -r DDF7DB817396A49B2A2723F7403BD972F75D9D76".to_string(), // Add a repository key
-        "pacman-key --lsign-key DDF7DB817396A49B2A2723F7403BD972F75D9D76".to_string(), // Sign the repository key
-        "pacman -Syu --noconfirm".to_string(), // Update the system
-        format!("pacman -S --noconfirm nfs-utils linux-headers zfs-dkms openssh networkmanager fish git {}-ucode", &platform), // Install packages
-        format!("useradd -m -G wheel -s /usr/bin/fish {}", username), // Create a user
-        format!("(echo '{}'; echo '{}') | passwd {}", password, password, username), // Set the user password
-        "zpool set cachefile=/etc/zfs/zpool.cache zroot".to_string(), // Set up the cache file
-        "bootctl install".to_string(), // Install the bootloader
-        "systemctl enable NetworkManager".to_string(), // Enable NetworkManager service
-        format!("echo -e 'title Arch Linux\nlinux vmlinuz-linux\ninitrd {}-ucode.img\ninitrd initramfs-linux.img\noptions zfs=zroot/ROOT/default rw' > /boot/loader/entries/arch.conf", &platform), // Configure the bootloader
-        "echo 'default arch' >> /boot/loader/loader.conf".to_string(), // Configure the bootloader
-        "echo '%wheel ALL=(ALL:ALL) ALL' >> /etc/sudoers".to_string(), // Allow wheel group to execute sudo
-        "systemctl enable zfs-scrub-weekly@zroot.timer".to_string(), // Enable ZFS scrub timer
-        "systemctl enable zfs.target".to_string(), // Enable ZFS target
-        "systemctl enable zfs-import-cache".to_string(),
-        "systemctl enable zfs-mount".to_string(), // Enable ZFS mount
-        "zgenhostid $(hostid)".to_string(), // Generate hostid for the system
-        "sed -i 's/keyboard keymap/keyboard zfs keymap/g' /etc/mkinitcpio.conf".to_string(), // Configure keyboard and keymap for initramfs
-        "mkinitcpio -P".to_string(), // Generate initramfs
-    ];
+// This function asks for everything the chroot stage needs that isn't in the plan and returns the plan with the answers filled in, so the ZFS stage can ask before it starts and pass them on into the chroot.
+pub fn chroot_answers(plan: &Plan) -> Result<Plan> {
+    let mut answers = plan.clone();
 
-    // Execute the commands in the vector
-    for command in commands {
-        execute_command(&command);
+    // Use the users from the plan, or prompt for a single username when the plan has none
+    if answers.users.is_empty() {
+        let username = prompt_valid(
+            "Enter username: ",
+            "username",
+            "a username starts with a lowercase letter or '_' followed by lowercase letters, digits, '_' or '-'",
+            is_valid_username,
+        )?;
+        answers.users.push(UserPlan {
+            name: username,
+            password: None,
+            shell: "/usr/bin/fish".to_string(),
+            groups: vec!["wheel".to_string()],
+        });
     }
 
-    // Return a `String` indicating the completion of the operation
-    Ok(format!("Chroot Install Done"))
+    // Prompt for the password of every user that doesn't have one in the plan
+    for user in answers.users.iter_mut() {
+        if user.password.is_none() {
+            user.password = Some(prompt_password(&user.name)?);
+        }
+    }
+
+    if answers.root.account == RootAccount::Password && answers.root.password.is_none() {
+        answers.root.password = Some(prompt_password("root")?);
+    }
+
+    if answers.system.platform.is_none() {
+        answers.system.platform = Some(prompt_valid(
+            "Enter your Platform in Lower Case(intel/amd): ",
+            "platform",
+            "the platform must be intel or amd",
+            is_valid_platform,
+        )?);
+    }
+
+    Ok(answers)
+}
+
+// Print the question and return the trimmed line the user typed.
+fn prompt(question: &str) -> Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_string())
+}
+
+// Ask the question until the answer is valid, giving up after three tries like the password prompt does.
+fn prompt_valid(
+    question: &str,
+    what: &str,
+    problem: &str,
+    valid: fn(&str) -> bool,
+) -> Result<String> {
+    for _ in 0..3 {
+        let answer = prompt(question)?;
+        if valid(&answer) {
+            return Ok(answer);
+        }
+        println!("Please try again, {}", problem);
+    }
+
+    Err(InstallError::InvalidInput(format!(
+        "No valid {} entered",
+        what
+    )))
+}
+
+// Ask for a new password for the user without echoing it, twice, until both match and the password passes the policy. Giving up after three tries keeps a script feeding the wrong input from looping forever.
+fn prompt_password(username: &str) -> Result<String> {
+    for _ in 0..3 {
+        let password = rpassword::prompt_password(format!("Enter password for {}: ", username))?;
+        if let Some(problem) = check_password(username, &password) {
+            println!("Please try again, {}", problem);
+            continue;
+        }
+
+        let confirmation =
+            rpassword::prompt_password(format!("Enter password for {} again: ", username))?;
+        if confirmation != password {
+            println!("Please try again, the passwords don't match");
+            continue;
+        }
+
+        return Ok(password);
+    }
+
+    Err(InstallError::InvalidInput(format!(
+        "No valid password entered for {}",
+        username
+    )))
 }
 
-pub fn execute_command(command: &str) -> std::io::Result<()> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&command)
-        .output()
-        .expect("Failed to execute command");
-
-    if !output.status.success() {
-        eprintln!(
-            "Command '{}' failed with exit status: {:?}",
-            command, output.status
+// This function installs packages and configures the ZFS filesystem in a chroot environment by executing a sequence of commands through the runner. The commands add a repository, install packages, create the users, set up a cache file, configure the bootloader, enable services, and generate an initramfs. The function takes the plan, the users with their passwords and the platform as input and returns a `String` indicating the completion of the operation.
+pub fn chroot_install(
+    plan: &Plan,
+    runner: &mut dyn Runner,
+    users: &[UserPlan],
+    platform: &str,
+) -> Result<String> {
+    let pool = &plan.pool.name;
+
+    // Define a vector of commands to execute, lines are added to files by passing them to tee on stdin
+    let mut commands = vec![
+        Cmd::new("tee")
+            .args(["-a", "/etc/pacman.conf"])
+            .stdin("[archzfs]\nServer = https://archzfs.com/$repo/$arch\n"), // Add a repository
+        Cmd::new("pacman-key").args(["-r", ARCHZFS_KEY]), // Add a repository key
+        Cmd::new("pacman-key").args(["--lsign-key", ARCHZFS_KEY]), // Sign the repository key
+        Cmd::new("pacman").args(["-Syu", "--noconfirm"]), // Update the system
+        Cmd::new("pacman")
+            .args(["-S", "--noconfirm"])
+            .args(&plan.packages.system)
+            .arg(format!("{}-ucode", platform)), // Install packages
+    ];
+
+    // Create the users and set their passwords, chpasswd reads them from stdin so they never show up in a command line
+    for user in users {
+        let password = user.password.as_deref().unwrap_or_default();
+        commands.push(
+            Cmd::new("useradd")
+                .args(["-m", "-G"])
+                .arg(user.groups.join(","))
+                .args(["-s", user.shell.as_str(), user.name.as_str()]),
         );
-        std::process::exit(1);
+        commands.push(Cmd::new("chpasswd").secret_stdin(format!("{}:{}\n", user.name, password)));
     }
 
-    println!("{}", String::from_utf8_lossy(&output.stdout));
+    // Set the password of root or lock it
+    match plan.root.account {
+        RootAccount::Unchanged => {}
+        RootAccount::Password => {
+            let password = plan.root.password.as_deref().unwrap_or_default();
+            commands.push(Cmd::new("chpasswd").secret_stdin(format!("root:{}\n", password)));
+        }
+        RootAccount::Locked => commands.push(Cmd::new("passwd").args(["-l", "root"])),
+    }
 
-    thread::sleep(Duration::from_secs(3));
+    commands.extend(vec![
+        Cmd::new("zpool")
+            .args(["set", "cachefile=/etc/zfs/zpool.cache", pool.as_str()]), // Set up the cache file
+        Cmd::new("bootctl").arg("install"), // Install the bootloader
+        Cmd::new("tee").arg("/boot/loader/entries/arch.conf").stdin(format!(
+            "title Arch Linux\nlinux vmlinuz-linux\ninitrd {}-ucode.img\ninitrd initramfs-linux.img\noptions zfs={} rw\n",
+            platform,
+            plan.root_dataset()
+        )), // Configure the bootloader
+        Cmd::new("tee")
+            .arg("-a")
+            .arg(format!("{}/loader/loader.conf", plan.partitions.esp_mountpoint()))
+            .stdin("default arch\n"), // Configure the bootloader, loader.conf is always on the ESP
+        Cmd::new("tee")
+            .args(["-a", "/etc/sudoers"])
+            .stdin("%wheel ALL=(ALL:ALL) ALL\n"), // Allow wheel group to execute sudo
+        Cmd::new("systemctl")
+            .arg("enable")
+            .arg(format!("zfs-scrub-weekly@{}.timer", pool)), // Enable ZFS scrub timer
+        Cmd::new("systemctl").args(["enable", "zfs.target"]), // Enable ZFS target
+        Cmd::new("systemctl").args(["enable", "zfs-import-cache"]),
+        Cmd::new("systemctl").args(["enable", "zfs-mount"]), // Enable ZFS mount
+    ]);
 
-    Ok(())
+    // Enable the extra services from the plan
+    for service in &plan.services.system {
+        commands.push(Cmd::new("systemctl").arg("enable").arg(service));
+    }
+
+    // Generate the hostid for the system from the current one, in a dry run there is none and zgenhostid makes one up
+    let hostid = runner
+        .execute(&Cmd::new("hostid"))?
+        .stdout
+        .trim()
+        .to_string();
+    commands.push(Cmd::new("zgenhostid").args((!hostid.is_empty()).then_some(hostid)));
+
+    // Add the zfs hook to the initramfs, for an encrypted pool it goes after keymap so the passphrase prompt uses the right layout
+    if plan.encryption.enabled {
+        commands.push(Cmd::new("sed").args([
+            "-i",
+            "s/keyboard keymap/keyboard keymap zfs/g",
+            "/etc/mkinitcpio.conf",
+        ]));
+        // A keyfile has to be in the initramfs for the zfs hook to unlock the pool with it
+        if plan.encryption.key == KeySource::Keyfile {
+            commands.push(
+                Cmd::new("sed")
+                    .arg("-i")
+                    .arg(format!("s|^FILES=(|FILES=({} |", plan.installed_keyfile()))
+                    .arg("/etc/mkinitcpio.conf"),
+            );
+        }
+    } else {
+        commands.push(Cmd::new("sed").args([
+            "-i",
+            "s/keyboard keymap/keyboard zfs keymap/g",
+            "/etc/mkinitcpio.conf",
+        ])); // Configure keyboard and keymap for initramfs
+    }
+
+    commands.push(Cmd::new("mkinitcpio").arg("-P")); // Generate initramfs
+
+    // Execute the commands in the vector as steps
+    execute_steps(runner, "chroot_install", &commands)?;
+
+    // Return a `String` indicating the completion of the operation
+    Ok("Chroot Install Done".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+
+    #[test]
+    fn install_creates_the_users_without_passwords_in_the_commands() {
+        let plan = Plan::parse(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "it's a 'secret'"
+
+            [root]
+            account = "locked"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+        chroot_install(&plan, &mut runner, &plan.users, "amd").unwrap();
+
+        let commands = &runner.commands;
+        let useradd = commands
+            .iter()
+            .position(|command| command == "useradd -m -G wheel -s /usr/bin/fish alice")
+            .unwrap();
+        assert_eq!(commands[useradd + 1], "chpasswd [stdin: hidden, 1 line]");
+        assert!(commands.contains(&"passwd -l root".to_string()));
+        assert!(commands.iter().all(|command| !command.contains("secret")));
+    }
+
+    #[test]
+    fn install_configures_the_boot_loader_and_initramfs() {
+        let plan = Plan::default();
+        let mut runner = RecordingRunner::new();
+        chroot_install(&plan, &mut runner, &[], "intel").unwrap();
+
+        let commands = &runner.commands;
+        assert_eq!(commands[0], "hostid");
+        assert!(commands.contains(&"pacman -S --noconfirm nfs-utils linux-headers zfs-dkms openssh networkmanager fish git intel-ucode".to_string()));
+        assert!(commands.contains(&"tee /boot/loader/entries/arch.conf [stdin: title Arch Linux\\nlinux vmlinuz-linux\\ninitrd intel-ucode.img\\ninitrd initramfs-linux.img\\noptions zfs=zroot/ROOT/default rw\\n]".to_string()));
+        assert!(commands.contains(&"zgenhostid".to_string()));
+        assert!(commands.contains(
+            &"sed -i 's/keyboard keymap/keyboard zfs keymap/g' /etc/mkinitcpio.conf".to_string()
+        ));
+        assert_eq!(commands.last().unwrap(), "mkinitcpio -P");
+    }
 }
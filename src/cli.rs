@@ -0,0 +1,295 @@
+use crate::error::{InstallError, Result};
+
+// What the installer was asked to do.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    // No subcommand, ask which stage to run
+    Menu,
+    Disk,
+    Chroot,
+    User,
+    Finish,
+    // The disk stage continuing into the chroot and finish stages
+    All,
+    PlanValidate,
+    Help,
+    Version,
+}
+
+// The global options, they can be given before or after the subcommand.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub plan: Option<String>,
+    pub dry_run: bool,
+    pub resume: bool,
+    pub yes: bool,
+    pub log: Option<String>,
+    pub target: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cli {
+    pub command: Command,
+    pub options: Options,
+}
+
+pub const HELP: &str = "\
+Install Arch Linux on ZFS
+
+Usage: install [OPTIONS] [COMMAND]
+
+Commands:
+  disk           Wipe and partition the drives, create the pool and install the base system
+  chroot         Set up the new system, run inside arch-chroot
+  user           Set up the home directory, AUR packages and dotfiles as your own user
+  finish         Snapshot, unmount and export the pool once the chroot stage is done
+  all            Run the disk stage and continue into the chroot and finish stages
+  plan validate  Check the install plan and exit
+  help           Print this help
+
+Without a command the installer asks which stage to run.
+
+Options:
+      --plan <PATH>    Read the install plan from the TOML file
+      --dry-run        Print every command instead of running it
+      --resume         Skip the steps that completed in an earlier run
+      --yes            Wipe the drives without typing their serials
+      --log <DIR>      Write the logs to the directory [default: /var/log/install,
+                       ~/.local/state/install/log for a normal user]
+      --target <DIR>   Mount the new system on the directory [default: /mnt]
+  -h, --help           Print this help
+  -V, --version        Print the version";
+
+// Parse the arguments after the executable name. Options take their value as the next argument or after an equals sign, like --plan=host.toml.
+pub fn parse(args: &[String]) -> Result<Cli> {
+    let mut options = Options::default();
+    let mut words: Vec<&str> = Vec::new();
+    let mut help = false;
+    let mut version = false;
+
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let (name, inline) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+
+        // The value of an option that takes one, an option that doesn't must not be given one. The next argument is only taken as the value when it isn't an option itself, a value starting with '-' has to be given after an equals sign
+        let mut value = |option: &str| -> Result<String> {
+            let value = match &inline {
+                Some(value) => Some(value.clone()),
+                None => args.next_if(|next| !next.starts_with('-')).cloned(),
+            };
+            match value {
+                Some(value) if !value.is_empty() => Ok(value),
+                _ => Err(usage(&format!("{} needs a value", option))),
+            }
+        };
+        let flag = |option: &str| -> Result<bool> {
+            match inline {
+                Some(_) => Err(usage(&format!("{} doesn't take a value", option))),
+                None => Ok(true),
+            }
+        };
+
+        match name {
+            "--plan" => options.plan = Some(value(name)?),
+            "--log" => options.log = Some(value(name)?),
+            "--target" => options.target = Some(value(name)?),
+            "--dry-run" => options.dry_run = flag(name)?,
+            "--resume" => options.resume = flag(name)?,
+            "--yes" => options.yes = flag(name)?,
+            "-h" | "--help" => help = flag(name)?,
+            "-V" | "--version" => version = flag(name)?,
+            // The flags of earlier versions keep working for existing scripts, they are left out of the help
+            "--yes-i-know" => options.yes = flag(name)?,
+            "--zfs" | "--chroot" | "--user" | "--finish" => {
+                flag(name)?;
+                words.push(if name == "--zfs" { "disk" } else { &name[2..] });
+            }
+            _ if name.starts_with('-') => {
+                return Err(usage(&format!("Unknown option '{}'", arg)));
+            }
+            _ => words.push(arg),
+        }
+    }
+
+    // The same rules as for the target and log directory in the plan
+    if let Some(target) = &options.target {
+        if !target.starts_with('/') || target.trim_matches('/').is_empty() {
+            return Err(usage(
+                "--target must be an absolute path to a directory other than /",
+            ));
+        }
+    }
+    if let Some(log) = &options.log {
+        if !log.starts_with('/') {
+            return Err(usage("--log must be an absolute path"));
+        }
+    }
+
+    // Asking for help or the version wins over everything else, so it works whatever else is on the command line
+    let command = if help {
+        Command::Help
+    } else if version {
+        Command::Version
+    } else {
+        match words.as_slice() {
+            [] => Command::Menu,
+            ["plan"] => return Err(usage("plan needs a subcommand, like plan validate")),
+            ["plan", "validate", rest @ ..] => expect_end(Command::PlanValidate, rest)?,
+            ["plan", command, ..] => {
+                return Err(usage(&format!("Unknown plan command '{}'", command)))
+            }
+            [command, rest @ ..] => {
+                let command = match *command {
+                    "disk" => Command::Disk,
+                    "chroot" => Command::Chroot,
+                    "user" => Command::User,
+                    "finish" => Command::Finish,
+                    "all" => Command::All,
+                    "help" => Command::Help,
+                    _ => return Err(usage(&format!("Unknown command '{}'", command))),
+                };
+                expect_end(command, rest)?
+            }
+        }
+    };
+
+    Ok(Cli { command, options })
+}
+
+// A command is complete, anything after it is reported as unexpected.
+fn expect_end(command: Command, rest: &[&str]) -> Result<Command> {
+    match rest.first() {
+        Some(extra) => Err(usage(&format!("Unexpected argument '{}'", extra))),
+        None => Ok(command),
+    }
+}
+
+pub fn usage(message: &str) -> InstallError {
+    InstallError::Usage(format!(
+        "{}\nRun 'install --help' to see the commands and options",
+        message
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Cli> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        parse(&args)
+    }
+
+    // The first line of the usage error, without the hint about --help.
+    fn usage_error(args: &[&str]) -> String {
+        match parse_args(args) {
+            Err(InstallError::Usage(message)) => message.lines().next().unwrap().to_string(),
+            other => panic!("expected a usage error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn subcommands_are_parsed() {
+        for (args, command) in [
+            (&[][..], Command::Menu),
+            (&["disk"][..], Command::Disk),
+            (&["chroot"][..], Command::Chroot),
+            (&["user"][..], Command::User),
+            (&["finish"][..], Command::Finish),
+            (&["all"][..], Command::All),
+            (&["plan", "validate"][..], Command::PlanValidate),
+            (&["help"][..], Command::Help),
+        ] {
+            assert_eq!(parse_args(args).unwrap().command, command, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn options_can_come_before_or_after_the_subcommand() {
+        let cli = parse_args(&[
+            "--plan",
+            "host.toml",
+            "disk",
+            "--dry-run",
+            "--resume",
+            "--yes",
+            "--log=/tmp/log",
+            "--target",
+            "/target",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.command, Command::Disk);
+        assert_eq!(cli.options.plan.as_deref(), Some("host.toml"));
+        assert_eq!(cli.options.log.as_deref(), Some("/tmp/log"));
+        assert_eq!(cli.options.target.as_deref(), Some("/target"));
+        assert!(cli.options.dry_run && cli.options.resume && cli.options.yes);
+    }
+
+    #[test]
+    fn values_can_be_given_after_an_equals_sign() {
+        let cli = parse_args(&["--plan=-host.toml", "chroot"]).unwrap();
+        assert_eq!(cli.options.plan.as_deref(), Some("-host.toml"));
+        assert_eq!(
+            usage_error(&["--dry-run=yes", "disk"]),
+            "--dry-run doesn't take a value"
+        );
+    }
+
+    #[test]
+    fn a_missing_value_is_reported() {
+        assert_eq!(usage_error(&["disk", "--plan"]), "--plan needs a value");
+        assert_eq!(usage_error(&["--plan=", "disk"]), "--plan needs a value");
+        // The next option isn't taken as the value
+        assert_eq!(
+            usage_error(&["--plan", "--dry-run", "disk"]),
+            "--plan needs a value"
+        );
+    }
+
+    #[test]
+    fn help_and_version_win_over_everything_else() {
+        assert_eq!(
+            parse_args(&["disk", "--version", "--help"])
+                .unwrap()
+                .command,
+            Command::Help
+        );
+        assert_eq!(
+            parse_args(&["bogus", "-V"]).unwrap().command,
+            Command::Version
+        );
+    }
+
+    #[test]
+    fn the_first_unexpected_argument_is_reported() {
+        assert_eq!(
+            usage_error(&["plan", "validate", "extra"]),
+            "Unexpected argument 'extra'"
+        );
+        assert_eq!(
+            usage_error(&["disk", "chroot", "user"]),
+            "Unexpected argument 'chroot'"
+        );
+        assert_eq!(usage_error(&["zfs"]), "Unknown command 'zfs'");
+        assert_eq!(
+            usage_error(&["plan", "check"]),
+            "Unknown plan command 'check'"
+        );
+        assert_eq!(usage_error(&["--force"]), "Unknown option '--force'");
+    }
+
+    #[test]
+    fn the_flags_of_earlier_versions_still_work() {
+        let cli = parse_args(&["--plan", "host.toml", "--yes-i-know", "--zfs"]).unwrap();
+        assert_eq!(cli.command, Command::Disk);
+        assert!(cli.options.yes);
+
+        assert_eq!(parse_args(&["--chroot"]).unwrap().command, Command::Chroot);
+        assert_eq!(parse_args(&["--user"]).unwrap().command, Command::User);
+        assert_eq!(parse_args(&["--finish"]).unwrap().command, Command::Finish);
+    }
+}
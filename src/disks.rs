@@ -0,0 +1,412 @@
+use crate::error::{InstallError, Result};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long udev gets to create device nodes after udevadm settle returned, slow USB disks can take a few seconds.
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How a disk is connected to the system.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    Nvme,
+    Sata,
+    Usb,
+    Virtio,
+    Scsi,
+    Unknown,
+}
+
+// A partition on a disk and what udev knows about its contents.
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub name: String,
+    pub fs_type: Option<String>,
+    pub label: Option<String>,
+    pub mountpoint: Option<String>,
+}
+
+// A physical disk from /sys/block together with all its names in /dev/disk/by-id.
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub name: String,
+    pub id: Option<String>,
+    pub aliases: Vec<String>,
+    pub size_bytes: u64,
+    pub physical_sector_size: u32,
+    pub logical_sector_size: u32,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub rotational: bool,
+    pub discard: bool,
+    pub transport: Transport,
+    pub fs_type: Option<String>,
+    pub label: Option<String>,
+    pub mountpoint: Option<String>,
+    pub partitions: Vec<Partition>,
+}
+
+impl Transport {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Nvme => "NVMe",
+            Transport::Sata => "SATA",
+            Transport::Usb => "USB",
+            Transport::Virtio => "virtio",
+            Transport::Scsi => "SCSI",
+            Transport::Unknown => "unknown",
+        }
+    }
+}
+
+impl Disk {
+    // The disk the live ISO was booted from, recognised by the ARCH_ label archiso gives its filesystem.
+    pub fn is_live_medium(&self) -> bool {
+        let is_archiso = |label: &Option<String>| {
+            label
+                .as_deref()
+                .is_some_and(|label| label.starts_with("ARCH_"))
+        };
+        is_archiso(&self.label)
+            || self
+                .partitions
+                .iter()
+                .any(|partition| is_archiso(&partition.label))
+    }
+
+    // Everywhere the disk or one of its partitions is mounted or used as swap.
+    pub fn mountpoints(&self) -> Vec<String> {
+        self.mountpoint
+            .iter()
+            .chain(
+                self.partitions
+                    .iter()
+                    .filter_map(|partition| partition.mountpoint.as_ref()),
+            )
+            .cloned()
+            .collect()
+    }
+
+    // The ZFS pools the disk is part of, udev uses the pool name as the label of a zfs_member.
+    pub fn zfs_pools(&self) -> Vec<String> {
+        let mut pools: Vec<String> = std::iter::once((&self.fs_type, &self.label))
+            .chain(
+                self.partitions
+                    .iter()
+                    .map(|partition| (&partition.fs_type, &partition.label)),
+            )
+            .filter(|(fs_type, _)| fs_type.as_deref() == Some("zfs_member"))
+            .map(|(_, label)| label.clone().unwrap_or_else(|| "unknown".to_string()))
+            .collect();
+        pools.sort();
+        pools.dedup();
+        pools
+    }
+
+    // A disk can be installed to when it has a /dev/disk/by-id name, isn't the live medium and nothing on it is in use. When resuming an install the partitions the earlier run mounted are expected to be in use.
+    pub fn unavailable_reason(&self, resume: bool) -> Option<String> {
+        if self.is_live_medium() {
+            return Some("it is the live boot medium".to_string());
+        }
+        let mountpoints = self.mountpoints();
+        if !mountpoints.is_empty() && !resume {
+            return Some(format!("it is in use at {}", mountpoints.join(", ")));
+        }
+        if self.id.is_none() {
+            return Some("it has no name in /dev/disk/by-id".to_string());
+        }
+        None
+    }
+
+    // A single line with everything needed to recognise the disk.
+    pub fn describe(&self) -> String {
+        let mut details = vec![format_size(self.size_bytes)];
+        if let Some(model) = &self.model {
+            details.push(model.clone());
+        }
+        if let Some(serial) = &self.serial {
+            details.push(format!("serial {}", serial));
+        }
+        details.push(if self.rotational { "HDD" } else { "SSD" }.to_string());
+        details.push(self.transport.name().to_string());
+        details.push(self.contents());
+
+        format!(
+            "{} ({})",
+            self.id.as_deref().unwrap_or(&self.name),
+            details.join(", ")
+        )
+    }
+
+    // A summary of what is on the disk, so a disk holding data stands out.
+    pub fn contents(&self) -> String {
+        let mut contents = Vec::new();
+        if !self.partitions.is_empty() {
+            contents.push(format!("{} partitions", self.partitions.len()));
+        }
+        if let Some(fs_type) = &self.fs_type {
+            if fs_type != "zfs_member" {
+                contents.push(format!("{} filesystem", fs_type));
+            }
+        }
+        let mut filesystems: Vec<&str> = self
+            .partitions
+            .iter()
+            .filter_map(|partition| partition.fs_type.as_deref())
+            .filter(|fs_type| *fs_type != "zfs_member")
+            .collect();
+        filesystems.sort();
+        filesystems.dedup();
+        if !filesystems.is_empty() {
+            contents.push(filesystems.join("/"));
+        }
+        for pool in self.zfs_pools() {
+            contents.push(format!("ZFS pool {}", pool));
+        }
+
+        if contents.is_empty() {
+            "empty".to_string()
+        } else {
+            contents.join(", ")
+        }
+    }
+}
+
+// List every disk the installer could use: everything in /sys/block except loop, ram, zram, optical and device-mapper devices.
+pub fn discover() -> Result<Vec<Disk>> {
+    let mounts = read_mounts();
+    let by_id = read_by_id();
+
+    let mut disks = Vec::new();
+    for entry in fs::read_dir("/sys/block")? {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        if ["loop", "ram", "zram", "sr", "dm-", "md", "fd"]
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+        {
+            continue;
+        }
+        disks.push(read_disk(&name, &mounts, &by_id));
+    }
+
+    disks.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(disks)
+}
+
+// Find a disk by its name in /dev/disk/by-id.
+pub fn find(id: &str) -> Result<Option<Disk>> {
+    Ok(discover()?
+        .into_iter()
+        .find(|disk| disk.aliases.iter().any(|alias| alias == id)))
+}
+
+fn read_disk(name: &str, mounts: &[(String, String)], by_id: &[(String, String)]) -> Disk {
+    let sys = Path::new("/sys/block").join(name);
+    let udev = read_udev(&sys);
+
+    // The path the /sys/block entry links to tells how the disk is connected
+    let device_path = fs::read_link(&sys)
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let transport = if name.starts_with("nvme") {
+        Transport::Nvme
+    } else if device_path.contains("/usb") {
+        Transport::Usb
+    } else if device_path.contains("/virtio") {
+        Transport::Virtio
+    } else if device_path.contains("/ata") {
+        Transport::Sata
+    } else if name.starts_with("sd") {
+        Transport::Scsi
+    } else {
+        Transport::Unknown
+    };
+
+    let mut aliases: Vec<String> = by_id
+        .iter()
+        .filter(|(_, target)| target == name)
+        .map(|(alias, _)| alias.clone())
+        .collect();
+    aliases.sort_by_key(|alias| (alias_rank(alias), alias.len()));
+
+    // Partitions are the subdirectories of the disk that have a partition file
+    let mut partitions = Vec::new();
+    if let Ok(entries) = fs::read_dir(&sys) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.join("partition").exists() {
+                let partition_name = entry.file_name().to_string_lossy().to_string();
+                let partition_udev = read_udev(&path);
+                partitions.push(Partition {
+                    mountpoint: mountpoint(mounts, &partition_name),
+                    fs_type: udev_value(&partition_udev, "ID_FS_TYPE"),
+                    label: udev_value(&partition_udev, "ID_FS_LABEL"),
+                    name: partition_name,
+                });
+            }
+        }
+    }
+    partitions.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Disk {
+        name: name.to_string(),
+        id: aliases.first().cloned(),
+        aliases,
+        size_bytes: read_number(&sys.join("size")).unwrap_or(0) * 512,
+        physical_sector_size: read_number(&sys.join("queue/physical_block_size")).unwrap_or(512)
+            as u32,
+        logical_sector_size: read_number(&sys.join("queue/logical_block_size")).unwrap_or(512)
+            as u32,
+        model: read_trimmed(&sys.join("device/model")).or_else(|| udev_value(&udev, "ID_MODEL")),
+        serial: read_trimmed(&sys.join("device/serial"))
+            .or_else(|| udev_value(&udev, "ID_SERIAL_SHORT")),
+        rotational: read_number(&sys.join("queue/rotational")) == Some(1),
+        // The kernel reports a maximum discard size of 0 for drives that can't discard
+        discard: read_number(&sys.join("queue/discard_max_bytes")).is_some_and(|max| max > 0),
+        transport,
+        fs_type: udev_value(&udev, "ID_FS_TYPE"),
+        label: udev_value(&udev, "ID_FS_LABEL"),
+        mountpoint: mountpoint(mounts, name),
+        partitions,
+    }
+}
+
+// Prefer the names that say what the disk is (ata-, nvme-, usb-) over the ones made from WWNs and EUIs, and the primary NVMe namespace name over the _1 duplicate.
+fn alias_rank(alias: &str) -> u8 {
+    if alias.starts_with("wwn-")
+        || alias.starts_with("nvme-eui.")
+        || alias.starts_with("nvme-nvme.")
+    {
+        2
+    } else if alias.ends_with("_1") {
+        1
+    } else {
+        0
+    }
+}
+
+// Every non-partition link in /dev/disk/by-id with the kernel name it points to.
+fn read_by_id() -> Vec<(String, String)> {
+    let mut links = Vec::new();
+    if let Ok(entries) = fs::read_dir("/dev/disk/by-id") {
+        for entry in entries.flatten() {
+            let alias = entry.file_name().to_string_lossy().to_string();
+            if alias.contains("-part") {
+                continue;
+            }
+            if let Ok(target) = fs::canonicalize(entry.path()) {
+                if let Some(target) = target.file_name() {
+                    links.push((alias, target.to_string_lossy().to_string()));
+                }
+            }
+        }
+    }
+    links
+}
+
+// The kernel name and mountpoint of every mounted block device and every active swap device.
+fn read_mounts() -> Vec<(String, String)> {
+    let mut mounts = Vec::new();
+    for line in fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+    {
+        let mut fields = line.split_whitespace();
+        if let (Some(source), Some(target)) = (fields.next(), fields.next()) {
+            if let Some(device) = source.strip_prefix("/dev/") {
+                mounts.push((device.to_string(), target.to_string()));
+            }
+        }
+    }
+    for line in fs::read_to_string("/proc/swaps")
+        .unwrap_or_default()
+        .lines()
+        .skip(1)
+    {
+        if let Some(device) = line
+            .split_whitespace()
+            .next()
+            .and_then(|source| source.strip_prefix("/dev/"))
+        {
+            mounts.push((device.to_string(), "[swap]".to_string()));
+        }
+    }
+    mounts
+}
+
+fn mountpoint(mounts: &[(String, String)], name: &str) -> Option<String> {
+    mounts
+        .iter()
+        .find(|(device, _)| device == name)
+        .map(|(_, target)| target.clone())
+}
+
+// The udev database entry of a block device, found by the major:minor number in its dev file.
+fn read_udev(sys: &Path) -> String {
+    read_trimmed(&sys.join("dev"))
+        .and_then(|dev| fs::read_to_string(format!("/run/udev/data/b{}", dev)).ok())
+        .unwrap_or_default()
+}
+
+fn udev_value(udev: &str, key: &str) -> Option<String> {
+    let prefix = format!("E:{}=", key);
+    udev.lines()
+        .find_map(|line| line.strip_prefix(&prefix))
+        .map(|value| value.to_string())
+}
+
+fn read_trimmed(path: &Path) -> Option<String> {
+    let value = fs::read_to_string(path).ok()?.trim().to_string();
+    (!value.is_empty()).then_some(value)
+}
+
+fn read_number(path: &Path) -> Option<u64> {
+    read_trimmed(path)?.parse().ok()
+}
+
+// The device node of a drive: a loop device the installer attached is used by its path, a physical disk by its name in /dev/disk/by-id.
+pub fn device_path(drive: &str) -> String {
+    if drive.starts_with('/') {
+        drive.to_string()
+    } else {
+        format!("/dev/disk/by-id/{}", drive)
+    }
+}
+
+// The device node of a partition of a drive, the kernel puts a p between a loop device and the partition number where /dev/disk/by-id adds -part.
+pub fn partition_path(drive: &str, number: usize) -> String {
+    if drive.starts_with('/') {
+        format!("{}p{}", drive, number)
+    } else {
+        format!("/dev/disk/by-id/{}-part{}", drive, number)
+    }
+}
+
+// Wait until every device node exists, polling since udev creates them asynchronously even after udevadm settle when a disk is slow to report its partitions.
+pub fn wait_for_devices(paths: &[String]) -> Result<()> {
+    let started = Instant::now();
+    while let Some(missing) = paths.iter().find(|path| !Path::new(path).exists()) {
+        if started.elapsed() > DEVICE_TIMEOUT {
+            return Err(InstallError::PreconditionFailed(format!(
+                "{} did not appear within {} seconds",
+                missing,
+                DEVICE_TIMEOUT.as_secs()
+            )));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+// Sizes in the binary units lsblk uses, with one decimal.
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
@@ -0,0 +1,110 @@
+use std::fmt;
+use std::io;
+
+// Every way a stage can fail. Stages return these up to main instead of exiting, main prints the summary and exits with the code for the kind of failure.
+#[derive(Debug)]
+pub enum InstallError {
+    // A command ran but exited unsuccessfully.
+    CommandFailed {
+        command: String,
+        status: Option<i32>,
+        stderr: String,
+    },
+    // The command line doesn't follow the usage, like an unknown option or subcommand.
+    Usage(String),
+    // Something the user typed or passed on the command line can't be used.
+    InvalidInput(String),
+    // The install plan could not be parsed or failed validation.
+    InvalidPlan(String),
+    // A program the installer needs is not installed.
+    MissingTool(String),
+    // The system is not in the state a stage needs before it can start.
+    PreconditionFailed(String),
+    // Reading or writing a file, or starting a command, failed.
+    Io(io::Error),
+    // Another error together with the step that was running when it happened.
+    Step {
+        step: String,
+        source: Box<InstallError>,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, InstallError>;
+
+impl InstallError {
+    // The exit code main uses for this error, 2 is the usual code for invalid command line usage.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            InstallError::CommandFailed { .. } => 1,
+            InstallError::Usage(_) => 2,
+            InstallError::InvalidInput(_) => 3,
+            InstallError::InvalidPlan(_) => 4,
+            InstallError::MissingTool(_) => 5,
+            InstallError::PreconditionFailed(_) => 6,
+            InstallError::Io(_) => 7,
+            InstallError::Step { source, .. } => source.exit_code(),
+        }
+    }
+}
+
+impl fmt::Display for InstallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InstallError::CommandFailed {
+                command,
+                status,
+                stderr,
+            } => {
+                match status {
+                    Some(status) => write!(
+                        f,
+                        "Command '{}' failed with exit status {}",
+                        command, status
+                    )?,
+                    None => write!(f, "Command '{}' was killed by a signal", command)?,
+                }
+                if !stderr.trim().is_empty() {
+                    write!(f, "\n{}", stderr.trim())?;
+                }
+                Ok(())
+            }
+            InstallError::Usage(message) => write!(f, "{}", message),
+            InstallError::InvalidInput(message) => write!(f, "Invalid input: {}", message),
+            InstallError::InvalidPlan(message) => write!(f, "Invalid install plan: {}", message),
+            InstallError::MissingTool(tool) => {
+                write!(f, "Required tool '{}' is not installed", tool)
+            }
+            InstallError::PreconditionFailed(message) => write!(f, "{}", message),
+            InstallError::Io(err) => write!(f, "{}", err),
+            InstallError::Step { step, source } => write!(f, "{} failed: {}", step, source),
+        }
+    }
+}
+
+impl std::error::Error for InstallError {}
+
+impl From<io::Error> for InstallError {
+    fn from(err: io::Error) -> Self {
+        InstallError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for InstallError {
+    fn from(err: serde_json::Error) -> Self {
+        InstallError::Io(err.into())
+    }
+}
+
+// Attach the step that was running to an error, so main can tell the user where the install stopped.
+pub trait StepContext<T> {
+    fn during(self, step: &str) -> Result<T>;
+}
+
+impl<T> StepContext<T> for Result<T> {
+    fn during(self, step: &str) -> Result<T> {
+        self.map_err(|err| InstallError::Step {
+            step: step.to_string(),
+            source: Box::new(err),
+        })
+    }
+}
@@ -0,0 +1,46 @@
+use crate::error::{Result, StepContext};
+use crate::log;
+use crate::plan::{PartitionKind, Plan};
+use crate::runner::{execute_steps, Cmd, Runner};
+
+// This function finishes the install from the live ISO once the chroot stage is done, leaving the pool exported so the installed system imports it on boot without complaining it was in use by another system.
+pub fn finish(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    finish_unmount_boot(plan, runner).during("Unmounting the boot partitions")?;
+    finish_snapshot(plan, runner).during("Taking the post-install snapshot")?;
+    // The datasets are still mounted, so the log can be copied into the new system with everything up to here
+    log::copy_to_target(plan, runner, "finish").during("Copying the install log")?;
+    finish_export_pool(plan, runner).during("Exporting the pool")?;
+
+    println!("The install is finished, you can reboot into the new system now");
+
+    Ok(())
+}
+
+// This function unmounts /boot in the target, and /efi when the ESP is mounted there because /boot is an XBOOTLDR partition.
+fn finish_unmount_boot(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    let mut commands = vec![Cmd::new("umount").arg(plan.in_target("/boot"))];
+    if plan.partitions.number(PartitionKind::Xbootldr).is_some() {
+        commands.push(Cmd::new("umount").arg(plan.in_target("/efi")));
+    }
+
+    execute_steps(runner, "finish_unmount_boot", &commands)
+}
+
+// This function takes a recursive snapshot of every dataset in the pool, so the freshly installed system can always be rolled back to.
+fn finish_snapshot(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    let commands = vec![Cmd::new("zfs")
+        .args(["snapshot", "-r"])
+        .arg(format!("{}@install", plan.pool.name))];
+
+    execute_steps(runner, "finish_snapshot", &commands)
+}
+
+// This function unmounts all ZFS datasets and exports the pool.
+fn finish_export_pool(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    let commands = vec![
+        Cmd::new("zfs").args(["umount", "-a"]),
+        Cmd::new("zpool").arg("export").arg(&plan.pool.name),
+    ];
+
+    execute_steps(runner, "finish_export_pool", &commands)
+}
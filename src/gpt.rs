@@ -0,0 +1,528 @@
+use crate::error::{InstallError, Result};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// The partition type GUIDs of the partitions the installer creates, with the sgdisk type code they are known by.
+pub const BIOS_BOOT: &str = "21686148-6449-6E6F-744E-656564454649"; // EF02
+pub const EFI_SYSTEM: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B"; // EF00
+pub const XBOOTLDR: &str = "BC13C2FF-59E6-4262-A352-B275FD6F7172"; // EA00
+pub const LINUX_SWAP: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F"; // 8200
+pub const SOLARIS_ZFS: &str = "6A898CC3-1DD2-11B2-99A6-080020736631"; // BF01
+
+// The table always has room for 128 entries of 128 bytes, like every other partitioning tool writes it.
+const ENTRY_COUNT: u64 = 128;
+const ENTRY_SIZE: u64 = 128;
+const HEADER_SIZE: usize = 92;
+
+// Partitions start on a MiB boundary, which is aligned for every sector and erase block size in use.
+const ALIGNMENT: u64 = 1024 * 1024;
+
+// A partition in the table, the LBAs are inclusive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GptPartition {
+    pub type_guid: [u8; 16],
+    pub guid: [u8; 16],
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub name: String,
+}
+
+// A GUID partition table for a disk of a given size, read from a disk or built up partition by partition and written out with a protective MBR and the primary and backup headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gpt {
+    pub sector_size: u64,
+    pub sectors: u64,
+    pub disk_guid: [u8; 16],
+    pub partitions: Vec<GptPartition>,
+}
+
+impl Gpt {
+    // An empty table for a disk of the given size in bytes.
+    pub fn new(sector_size: u64, disk_bytes: u64) -> Result<Gpt> {
+        Ok(Gpt {
+            sector_size,
+            sectors: disk_bytes / sector_size,
+            disk_guid: random_guid()?,
+            partitions: Vec::new(),
+        })
+    }
+
+    // The sectors the entry array takes up, 32 with 512 byte sectors and 4 with 4K sectors.
+    fn entry_sectors(&self) -> u64 {
+        (ENTRY_COUNT * ENTRY_SIZE).div_ceil(self.sector_size)
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        2 + self.entry_sectors()
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        self.sectors - 2 - self.entry_sectors()
+    }
+
+    // Add a partition after the last one, starting on the next MiB boundary. Without a size it takes everything up to the reserved bytes before the end of the usable space.
+    pub fn add(
+        &mut self,
+        type_guid: &str,
+        name: &str,
+        size: Option<u64>,
+        reserved: u64,
+    ) -> Result<()> {
+        let alignment = (ALIGNMENT / self.sector_size).max(1);
+        let next_free = self
+            .partitions
+            .last()
+            .map_or(self.first_usable_lba(), |partition| partition.last_lba + 1);
+        let first_lba = next_free.div_ceil(alignment) * alignment;
+
+        let last_lba = match size {
+            Some(size) => first_lba + size.div_ceil(self.sector_size) - 1,
+            None => self
+                .last_usable_lba()
+                .saturating_sub(reserved.div_ceil(self.sector_size)),
+        };
+
+        if last_lba > self.last_usable_lba() || last_lba < first_lba {
+            return Err(InstallError::PreconditionFailed(format!(
+                "Partition {} doesn't fit on a disk of {} sectors",
+                name, self.sectors
+            )));
+        }
+
+        self.partitions.push(GptPartition {
+            type_guid: parse_guid(type_guid),
+            guid: random_guid()?,
+            first_lba,
+            last_lba,
+            name: name.to_string(),
+        });
+        Ok(())
+    }
+
+    // Write the protective MBR, the primary header and entries at the start of the disk and the backup entries and header at the end, then flush them to the disk.
+    pub fn write(&self, file: &mut File) -> Result<()> {
+        let entries = self.entries();
+        let last_lba = self.sectors - 1;
+        let backup_entries_lba = last_lba - self.entry_sectors();
+
+        write_at(file, 0, &self.protective_mbr())?;
+        write_at(
+            file,
+            self.sector_size,
+            &self.header(1, last_lba, 2, &entries),
+        )?;
+        write_at(file, 2 * self.sector_size, &entries)?;
+        write_at(file, backup_entries_lba * self.sector_size, &entries)?;
+        write_at(
+            file,
+            last_lba * self.sector_size,
+            &self.header(last_lba, 1, backup_entries_lba, &entries),
+        )?;
+
+        file.sync_all()?;
+        Ok(())
+    }
+
+    // Read the table from a disk, checking the signatures and checksums of both headers and their entries.
+    pub fn read(file: &mut File, sector_size: u64) -> Result<Gpt> {
+        let sectors = file.seek(SeekFrom::End(0))? / sector_size;
+        let primary = read_header(file, sector_size, 1)?;
+        let backup = read_header(file, sector_size, primary.backup_lba)?;
+
+        if backup.backup_lba != 1 || backup.disk_guid != primary.disk_guid {
+            return Err(invalid(
+                "the backup header doesn't match the primary header",
+            ));
+        }
+        if primary.backup_lba != sectors - 1 {
+            return Err(invalid("the backup header is not at the end of the disk"));
+        }
+
+        let mut partitions = Vec::new();
+        for entry in primary.entries.chunks(ENTRY_SIZE as usize) {
+            let type_guid: [u8; 16] = entry[0..16].try_into().unwrap_or_default();
+            if type_guid == [0; 16] {
+                continue;
+            }
+
+            let name: Vec<u16> = entry[56..128]
+                .chunks(2)
+                .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                .take_while(|unit| *unit != 0)
+                .collect();
+            partitions.push(GptPartition {
+                type_guid,
+                guid: entry[16..32].try_into().unwrap_or_default(),
+                first_lba: le_u64(&entry[32..40]),
+                last_lba: le_u64(&entry[40..48]),
+                name: String::from_utf16_lossy(&name),
+            });
+        }
+
+        Ok(Gpt {
+            sector_size,
+            sectors,
+            disk_guid: primary.disk_guid,
+            partitions,
+        })
+    }
+
+    // A single MBR partition of type 0xEE covering the disk, so tools that only know MBR see the disk as in use.
+    fn protective_mbr(&self) -> Vec<u8> {
+        let mut mbr = vec![0; self.sector_size as usize];
+        let size = (self.sectors - 1).min(u32::MAX as u64) as u32;
+
+        mbr[446..462].copy_from_slice(&[
+            0x00, // Not bootable
+            0x00, 0x02, 0x00, // CHS of the first sector
+            0xEE, // GPT protective
+            0xFF, 0xFF, 0xFF, // CHS of the last sector, out of range
+            0x01, 0x00, 0x00, 0x00, // Starting at LBA 1
+            0, 0, 0, 0, // Size, filled in below
+        ]);
+        mbr[458..462].copy_from_slice(&size.to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xAA;
+        mbr
+    }
+
+    // The entry array with every partition followed by empty entries.
+    fn entries(&self) -> Vec<u8> {
+        let mut entries = vec![0; (ENTRY_COUNT * ENTRY_SIZE) as usize];
+        for (index, partition) in self.partitions.iter().enumerate() {
+            let entry =
+                &mut entries[index * ENTRY_SIZE as usize..(index + 1) * ENTRY_SIZE as usize];
+            entry[0..16].copy_from_slice(&partition.type_guid);
+            entry[16..32].copy_from_slice(&partition.guid);
+            entry[32..40].copy_from_slice(&partition.first_lba.to_le_bytes());
+            entry[40..48].copy_from_slice(&partition.last_lba.to_le_bytes());
+            // The name is up to 36 UTF-16 code units, the attributes at 48..56 stay empty
+            for (unit, bytes) in partition
+                .name
+                .encode_utf16()
+                .take(36)
+                .zip(entry[56..128].chunks_mut(2))
+            {
+                bytes.copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        entries
+    }
+
+    // A header at the given LBA pointing at the other header and at its own copy of the entries, padded to a full sector.
+    fn header(&self, lba: u64, other_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; self.sector_size as usize];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes()); // Revision 1.0
+        header[12..16].copy_from_slice(&(HEADER_SIZE as u32).to_le_bytes());
+        header[24..32].copy_from_slice(&lba.to_le_bytes());
+        header[32..40].copy_from_slice(&other_lba.to_le_bytes());
+        header[40..48].copy_from_slice(&self.first_usable_lba().to_le_bytes());
+        header[48..56].copy_from_slice(&self.last_usable_lba().to_le_bytes());
+        header[56..72].copy_from_slice(&self.disk_guid);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&(ENTRY_COUNT as u32).to_le_bytes());
+        header[84..88].copy_from_slice(&(ENTRY_SIZE as u32).to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(entries).to_le_bytes());
+
+        // The header checksum is taken with its own field set to zero
+        let checksum = crc32(&header[..HEADER_SIZE]);
+        header[16..20].copy_from_slice(&checksum.to_le_bytes());
+        header
+    }
+}
+
+// The parts of a header the reader needs, with the entries it points at.
+struct Header {
+    backup_lba: u64,
+    disk_guid: [u8; 16],
+    entries: Vec<u8>,
+}
+
+fn read_header(file: &mut File, sector_size: u64, lba: u64) -> Result<Header> {
+    let mut header = vec![0; sector_size as usize];
+    file.seek(SeekFrom::Start(lba * sector_size))?;
+    file.read_exact(&mut header)?;
+
+    if &header[0..8] != b"EFI PART" {
+        return Err(invalid(&format!("there is no GPT header at LBA {}", lba)));
+    }
+    let size = u32::from_le_bytes(header[12..16].try_into().unwrap_or_default()) as usize;
+    if !(HEADER_SIZE..=sector_size as usize).contains(&size) {
+        return Err(invalid(&format!(
+            "the header at LBA {} has a bad size",
+            lba
+        )));
+    }
+
+    let stored = u32::from_le_bytes(header[16..20].try_into().unwrap_or_default());
+    let mut zeroed = header[..size].to_vec();
+    zeroed[16..20].fill(0);
+    if crc32(&zeroed) != stored {
+        return Err(invalid(&format!(
+            "the header at LBA {} has a bad checksum",
+            lba
+        )));
+    }
+
+    let count = u32::from_le_bytes(header[80..84].try_into().unwrap_or_default()) as u64;
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap_or_default()) as u64;
+    if entry_size != ENTRY_SIZE || count > 1024 {
+        return Err(invalid(&format!(
+            "the header at LBA {} has an unsupported entry array",
+            lba
+        )));
+    }
+
+    let mut entries = vec![0; (count * entry_size) as usize];
+    file.seek(SeekFrom::Start(le_u64(&header[72..80]) * sector_size))?;
+    file.read_exact(&mut entries)?;
+    if crc32(&entries) != u32::from_le_bytes(header[88..92].try_into().unwrap_or_default()) {
+        return Err(invalid(&format!(
+            "the entries of the header at LBA {} have a bad checksum",
+            lba
+        )));
+    }
+
+    Ok(Header {
+        backup_lba: le_u64(&header[32..40]),
+        disk_guid: header[56..72].try_into().unwrap_or_default(),
+        entries,
+    })
+}
+
+fn write_at(file: &mut File, offset: u64, bytes: &[u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().unwrap_or_default())
+}
+
+fn invalid(problem: &str) -> InstallError {
+    InstallError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid GPT: {}", problem),
+    ))
+}
+
+// GUIDs are stored with their first three groups little endian and the last two as they are written.
+pub fn parse_guid(guid: &str) -> [u8; 16] {
+    let hex: Vec<u8> = guid
+        .split('-')
+        .collect::<String>()
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap_or("00"), 16).unwrap_or(0))
+        .collect();
+
+    let mut bytes = [0; 16];
+    if hex.len() == 16 {
+        bytes.copy_from_slice(&hex);
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+    }
+    bytes
+}
+
+// A version 4 GUID from the kernel's random number generator.
+fn random_guid() -> Result<[u8; 16]> {
+    let mut guid = [0; 16];
+    File::open("/dev/urandom")?.read_exact(&mut guid)?;
+    guid[7] = (guid[7] & 0x0F) | 0x40; // Version 4, the high nibble of the third group which is stored little endian
+    guid[8] = (guid[8] & 0x3F) | 0x80; // RFC 4122 variant
+    Ok(guid)
+}
+
+// The CRC32 (IEEE 802.3, reflected) GPT uses for its headers and entries.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+
+    const DISK_BYTES: u64 = 64 * 1024 * 1024;
+
+    // A sparse disk image in the temp directory, removed again when the test is done with it.
+    struct Image {
+        path: PathBuf,
+        file: File,
+    }
+
+    impl Image {
+        fn new(name: &str) -> Image {
+            let path = std::env::temp_dir().join(format!(
+                "install-gpt-{}-{}.img",
+                std::process::id(),
+                name
+            ));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            file.set_len(DISK_BYTES).unwrap();
+            Image { path, file }
+        }
+
+        fn bytes_at(&mut self, offset: u64, length: usize) -> Vec<u8> {
+            let mut bytes = vec![0; length];
+            self.file.seek(SeekFrom::Start(offset)).unwrap();
+            self.file.read_exact(&mut bytes).unwrap();
+            bytes
+        }
+
+        // Flip a byte, as a torn write or a bad sector would.
+        fn corrupt(&mut self, offset: u64) {
+            let byte = self.bytes_at(offset, 1)[0];
+            write_at(&mut self.file, offset, &[!byte]).unwrap();
+        }
+    }
+
+    impl Drop for Image {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    // The default layout: BIOS boot, the EFI system partition and a pool partition leaving 1 MiB free at the end.
+    fn layout(sector_size: u64) -> Gpt {
+        let mut gpt = Gpt::new(sector_size, DISK_BYTES).unwrap();
+        gpt.add(BIOS_BOOT, "BIOS boot", Some(1024 * 1024), 0)
+            .unwrap();
+        gpt.add(EFI_SYSTEM, "EFI system", Some(8 * 1024 * 1024), 0)
+            .unwrap();
+        gpt.add(SOLARIS_ZFS, "zfs", None, 1024 * 1024).unwrap();
+        gpt
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn guids_are_stored_mixed_endian() {
+        assert_eq!(
+            parse_guid(EFI_SYSTEM),
+            [
+                0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E,
+                0xC9, 0x3B
+            ]
+        );
+    }
+
+    #[test]
+    fn partitions_are_aligned_and_leave_the_reserved_space() {
+        let gpt = layout(512);
+        assert_eq!(gpt.sectors, 131072);
+        assert_eq!(gpt.first_usable_lba(), 34);
+        assert_eq!(gpt.last_usable_lba(), 131038);
+
+        let lbas: Vec<(u64, u64)> = gpt
+            .partitions
+            .iter()
+            .map(|partition| (partition.first_lba, partition.last_lba))
+            .collect();
+        assert_eq!(
+            lbas,
+            vec![(2048, 4095), (4096, 20479), (20480, 131038 - 2048)]
+        );
+    }
+
+    #[test]
+    fn partitions_that_dont_fit_are_refused() {
+        let mut gpt = Gpt::new(512, DISK_BYTES).unwrap();
+        assert!(gpt.add(SOLARIS_ZFS, "zfs", Some(DISK_BYTES), 0).is_err());
+        assert!(gpt.add(SOLARIS_ZFS, "zfs", None, DISK_BYTES).is_err());
+        assert!(gpt.partitions.is_empty());
+    }
+
+    #[test]
+    fn a_written_table_reads_back_the_same() {
+        let mut image = Image::new("round-trip");
+        let gpt = layout(512);
+        gpt.write(&mut image.file).unwrap();
+
+        assert_eq!(Gpt::read(&mut image.file, 512).unwrap(), gpt);
+        assert_eq!(image.bytes_at(512, 8), b"EFI PART");
+        assert_eq!(image.bytes_at(DISK_BYTES - 512, 8), b"EFI PART");
+    }
+
+    #[test]
+    fn the_protective_mbr_covers_the_disk() {
+        let mut image = Image::new("mbr");
+        layout(512).write(&mut image.file).unwrap();
+
+        let mbr = image.bytes_at(0, 512);
+        assert_eq!(mbr[446], 0x00);
+        assert_eq!(mbr[450], 0xEE);
+        assert_eq!(mbr[454..458], 1u32.to_le_bytes());
+        assert_eq!(mbr[458..462], (131072u32 - 1).to_le_bytes());
+        assert_eq!(mbr[462..510], [0; 48]);
+        assert_eq!(mbr[510..512], [0x55, 0xAA]);
+    }
+
+    #[test]
+    fn a_4k_sector_table_is_aligned_and_reads_back() {
+        let mut image = Image::new("4k");
+        let gpt = layout(4096);
+        assert_eq!(gpt.sectors, 16384);
+        assert_eq!(gpt.first_usable_lba(), 6);
+        assert_eq!(gpt.last_usable_lba(), 16378);
+        assert_eq!(gpt.partitions[0].first_lba, 256);
+        assert_eq!(gpt.partitions[2].last_lba, 16378 - 256);
+
+        gpt.write(&mut image.file).unwrap();
+        assert_eq!(Gpt::read(&mut image.file, 4096).unwrap(), gpt);
+
+        let mbr = image.bytes_at(0, 512);
+        assert_eq!(mbr[458..462], (16384u32 - 1).to_le_bytes());
+        assert_eq!(mbr[510..512], [0x55, 0xAA]);
+        assert_eq!(image.bytes_at(4096, 8), b"EFI PART");
+        assert_eq!(image.bytes_at(DISK_BYTES - 4096, 8), b"EFI PART");
+    }
+
+    #[test]
+    fn a_corrupted_header_is_rejected() {
+        let mut image = Image::new("bad-header");
+        layout(512).write(&mut image.file).unwrap();
+
+        // The first usable LBA in the primary header
+        image.corrupt(512 + 40);
+        let err = Gpt::read(&mut image.file, 512).unwrap_err();
+        assert!(err.to_string().contains("bad checksum"), "{}", err);
+    }
+
+    #[test]
+    fn a_corrupted_entry_array_is_rejected() {
+        let mut image = Image::new("bad-entries");
+        layout(512).write(&mut image.file).unwrap();
+
+        // The name of the first partition in the backup entries
+        image.corrupt(DISK_BYTES - 33 * 512 + 56);
+        let err = Gpt::read(&mut image.file, 512).unwrap_err();
+        assert!(err.to_string().contains("entries"), "{}", err);
+    }
+}
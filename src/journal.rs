@@ -0,0 +1,270 @@
+use crate::error::Result;
+use crate::runner::{Cmd, CommandOutput, Runner};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+// Where the journal is kept when running as root. On the live ISO this survives until reboot, inside the chroot it lands in the installed system.
+pub const JOURNAL_PATH: &str = "/var/lib/install/journal.json";
+
+// Where a normal user keeps the installer's state, like the user stage which can't write to /var/lib: $XDG_STATE_HOME/install, or ~/.local/state/install when that isn't set.
+pub fn user_state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .map(|dir| dir.join("install"))
+}
+
+// The journal of whoever runs the installer, root keeps it in /var/lib/install and everyone else in their state directory.
+pub fn journal_path() -> PathBuf {
+    if users::get_effective_uid() == 0 {
+        return PathBuf::from(JOURNAL_PATH);
+    }
+
+    user_state_dir()
+        .map(|dir| dir.join("journal.json"))
+        .unwrap_or_else(|| PathBuf::from(JOURNAL_PATH))
+}
+
+// The name the plan is copied to next to the journal in the new system.
+pub const PLAN_FILE: &str = "plan.toml";
+
+// A completed step, the fingerprint is a hash of the command and its input so a step only counts as done when the same command ran, for example on the same drive. Secret input like passwords is left out, a hash of it would be easy to brute force.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct JournalEntry {
+    id: String,
+    fingerprint: String,
+}
+
+// The list of completed steps, written to disk after every step so a failed install can be resumed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    #[serde(skip)]
+    path: PathBuf,
+    completed: Vec<JournalEntry>,
+}
+
+impl Journal {
+    // Start an empty journal at the path, the journal of an earlier install is overwritten once the first step completes.
+    pub fn start(path: &Path) -> Journal {
+        Journal {
+            path: path.to_path_buf(),
+            completed: Vec::new(),
+        }
+    }
+
+    // Load the journal at the path to continue an earlier install, an install that never got to write one starts empty.
+    pub fn resume(path: &Path) -> Result<Journal> {
+        if !path.exists() {
+            return Ok(Journal::start(path));
+        }
+
+        let contents = fs::read_to_string(path)?;
+        let mut journal: Journal = serde_json::from_str(&contents)?;
+        journal.path = path.to_path_buf();
+
+        Ok(journal)
+    }
+
+    fn is_done(&self, id: &str, command: &str) -> bool {
+        self.completed.contains(&entry(id, command))
+    }
+
+    // Record the step as completed and write the journal out straight away.
+    fn mark_done(&mut self, id: &str, command: &str) -> Result<()> {
+        self.completed.push(entry(id, command));
+
+        // Only root or the user running the stage can read the journal, it is copied into the installed system as it is
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&self.path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+fn entry(id: &str, command: &str) -> JournalEntry {
+    let mut hasher = DefaultHasher::new();
+    command.hash(&mut hasher);
+
+    JournalEntry {
+        id: id.to_string(),
+        fingerprint: format!("{:016x}", hasher.finish()),
+    }
+}
+
+// The input is part of the fingerprint through the way the command is shown, a line added to a file is only done when it was the same line. Secret input isn't, a step with one only counts as done when the same command ran.
+fn fingerprint(command: &Cmd) -> String {
+    command.to_string()
+}
+
+// Wraps another runner and keeps the journal up to date, steps already in the journal are skipped.
+pub struct JournalRunner<R: Runner> {
+    inner: R,
+    journal: Journal,
+}
+
+impl<R: Runner> JournalRunner<R> {
+    pub fn new(inner: R, journal: Journal) -> Self {
+        JournalRunner { inner, journal }
+    }
+}
+
+impl<R: Runner> Runner for JournalRunner<R> {
+    fn run(&mut self, command: &Cmd) -> Result<CommandOutput> {
+        self.inner.run(command)
+    }
+
+    fn dry_run(&self) -> bool {
+        self.inner.dry_run()
+    }
+
+    fn step_done(&self, id: &str, command: &Cmd) -> bool {
+        self.journal.is_done(id, &fingerprint(command))
+    }
+
+    fn execute_step(&mut self, id: &str, command: &Cmd) -> Result<()> {
+        let fingerprint = fingerprint(command);
+        if self.journal.is_done(id, &fingerprint) {
+            println!("Skipping {}, it completed in an earlier run", id);
+            return Ok(());
+        }
+
+        self.execute(command)?;
+        self.journal.mark_done(id, &fingerprint)
+    }
+
+    fn perform_step(
+        &mut self,
+        id: &str,
+        description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        if self.journal.is_done(id, description) {
+            println!("Skipping {}, it completed in an earlier run", id);
+            return Ok(());
+        }
+
+        self.inner.perform_step(id, description, action)?;
+        self.journal.mark_done(id, description)
+    }
+
+    fn perform_always(
+        &mut self,
+        id: &str,
+        description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        self.inner.perform_always(id, description, action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+
+    // A journal in the temp directory, removed again when the test is done with it.
+    struct TempJournal(PathBuf);
+
+    impl TempJournal {
+        fn new(name: &str) -> TempJournal {
+            let path = env::temp_dir().join(format!(
+                "install-journal-{}-{}.json",
+                std::process::id(),
+                name
+            ));
+            let _ = fs::remove_file(&path);
+            TempJournal(path)
+        }
+
+        fn runner(&self) -> JournalRunner<RecordingRunner> {
+            JournalRunner::new(RecordingRunner::new(), Journal::resume(&self.0).unwrap())
+        }
+    }
+
+    impl Drop for TempJournal {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn completed_steps_are_skipped_when_resuming() {
+        let journal = TempJournal::new("skip");
+        let mkdir = Cmd::new("mkdir").arg("/mnt/etc");
+
+        let mut runner = journal.runner();
+        runner.execute_step("setup/0", &mkdir).unwrap();
+        runner.execute_step("setup/0", &mkdir).unwrap();
+        assert_eq!(runner.inner.commands, ["mkdir /mnt/etc"]);
+
+        let mut resumed = journal.runner();
+        assert!(resumed.step_done("setup/0", &mkdir));
+        resumed.execute_step("setup/0", &mkdir).unwrap();
+        resumed
+            .perform_step("setup/1", "Write a GPT", &mut || Ok(()))
+            .unwrap();
+        assert_eq!(resumed.inner.commands, ["Write a GPT"]);
+
+        let mut again = journal.runner();
+        again
+            .perform_step("setup/1", "Write a GPT", &mut || Ok(()))
+            .unwrap();
+        assert!(again.inner.commands.is_empty());
+    }
+
+    #[test]
+    fn a_different_command_or_input_runs_the_step_again() {
+        let journal = TempJournal::new("rerun");
+        let tee = |line: &str| Cmd::new("tee").args(["-a", "/etc/fstab"]).stdin(line);
+
+        let mut runner = journal.runner();
+        runner.execute_step("fstab/0", &tee("a\n")).unwrap();
+
+        let mut resumed = journal.runner();
+        resumed.execute_step("fstab/0", &tee("b\n")).unwrap();
+        resumed
+            .execute_step("fstab/0", &Cmd::new("tee").arg("/etc/fstab").stdin("a\n"))
+            .unwrap();
+        // The same command under another id isn't the same step either
+        resumed.execute_step("fstab/1", &tee("a\n")).unwrap();
+        assert_eq!(resumed.inner.commands.len(), 3);
+    }
+
+    #[test]
+    fn secret_input_is_left_out_of_the_fingerprint() {
+        let journal = TempJournal::new("secret");
+        let chpasswd =
+            |password: &str| Cmd::new("chpasswd").secret_stdin(format!("alice:{}\n", password));
+
+        let mut runner = journal.runner();
+        runner
+            .execute_step("users/1", &chpasswd("hunter2hunter2"))
+            .unwrap();
+
+        let contents = fs::read_to_string(&journal.0).unwrap();
+        assert!(!contents.contains("hunter2"));
+        assert_eq!(
+            fs::metadata(&journal.0).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+
+        // Another password for the same user is still the same step
+        let resumed = journal.runner();
+        assert!(resumed.step_done("users/1", &chpasswd("correcthorse")));
+    }
+}
@@ -0,0 +1,203 @@
+use crate::error::Result;
+use crate::plan::Plan;
+use crate::runner::{Cmd, CommandOutput, Runner};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Where the log is kept. On the live ISO this survives until reboot, which is why the stages running there copy it into the new system, inside the chroot it lands in the installed system straight away.
+pub const LOG_DIR: &str = "/var/log/install";
+
+// The log written on the live ISO is copied into the new system under these names, so it doesn't overwrite the log of the chroot stage.
+const LIVE_LOG: &str = "live.log";
+const LIVE_JSON_LOG: &str = "live.jsonl";
+
+// Every run appends to a plain text log for reading and a JSON lines log for tools.
+pub struct Log {
+    text: PathBuf,
+    json: PathBuf,
+}
+
+impl Log {
+    pub fn new(dir: &Path) -> Log {
+        Log {
+            text: dir.join("install.log"),
+            json: dir.join("install.jsonl"),
+        }
+    }
+
+    // Append a record to both logs, opening them for every record so nothing is lost when the installer is killed.
+    fn append(&self, text: &str, json: serde_json::Value) -> Result<()> {
+        if let Some(parent) = self.text.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.text)?;
+        file.write_all(text.as_bytes())?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.json)?;
+        writeln!(file, "{}", json)?;
+
+        Ok(())
+    }
+
+    fn command(&self, output: &CommandOutput) -> Result<()> {
+        let status = output
+            .status
+            .map_or("none".to_string(), |status| status.to_string());
+        let mut text = format!("[{}] $ {}\n", timestamp(), output.command);
+        for line in output.stdout.lines() {
+            text.push_str(&format!("  {}\n", line));
+        }
+        for line in output.stderr.lines() {
+            text.push_str(&format!("! {}\n", line));
+        }
+        text.push_str(&format!(
+            "  exit status {} after {:.1}s\n",
+            status,
+            output.duration.as_secs_f64()
+        ));
+
+        // Secret input on stdin is left out, it holds passwords
+        let stdin = output
+            .command
+            .stdin
+            .as_ref()
+            .filter(|_| !output.command.secret);
+        self.append(
+            &text,
+            serde_json::json!({
+                "time": timestamp(),
+                "command": output.command.to_string(),
+                "program": output.command.program,
+                "args": output.command.args,
+                "stdin": stdin,
+                "dir": output.command.dir,
+                "status": output.status,
+                "stdout": output.stdout,
+                "stderr": output.stderr,
+                "duration_ms": output.duration.as_millis() as u64,
+            }),
+        )
+    }
+
+    fn step(
+        &self,
+        id: &str,
+        description: &str,
+        result: &Result<()>,
+        started: Instant,
+    ) -> Result<()> {
+        let duration = started.elapsed();
+        let error = result.as_ref().err().map(|err| err.to_string());
+        let text = format!(
+            "[{}] {}\n  {} after {:.1}s\n",
+            timestamp(),
+            description,
+            error.as_deref().unwrap_or("done"),
+            duration.as_secs_f64()
+        );
+
+        self.append(
+            &text,
+            serde_json::json!({
+                "time": timestamp(),
+                "step": id,
+                "description": description,
+                "error": error,
+                "duration_ms": duration.as_millis() as u64,
+            }),
+        )
+    }
+}
+
+// Seconds since the epoch, the live ISO has no timezone set up so local time would only mislead.
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// Wraps another runner and logs every command it runs and every step it performs.
+pub struct LogRunner<R: Runner> {
+    inner: R,
+    log: Log,
+}
+
+impl<R: Runner> LogRunner<R> {
+    pub fn new(inner: R, log: Log) -> Self {
+        LogRunner { inner, log }
+    }
+}
+
+impl<R: Runner> Runner for LogRunner<R> {
+    // A log that can't be written only warns, the command already ran and its step must still reach the journal.
+    fn run(&mut self, command: &Cmd) -> Result<CommandOutput> {
+        let output = self.inner.run(command)?;
+        if let Err(err) = self.log.command(&output) {
+            eprintln!("Warning: could not write the install log: {}", err);
+        }
+        Ok(output)
+    }
+
+    fn dry_run(&self) -> bool {
+        self.inner.dry_run()
+    }
+
+    fn perform_step(
+        &mut self,
+        id: &str,
+        description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let result = self.inner.perform_step(id, description, action);
+        if let Err(err) = self.log.step(id, description, &result, started) {
+            eprintln!("Warning: could not write the install log: {}", err);
+        }
+        result
+    }
+
+    fn perform_always(
+        &mut self,
+        id: &str,
+        description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let result = self.inner.perform_always(id, description, action);
+        if let Err(err) = self.log.step(id, description, &result, started) {
+            eprintln!("Warning: could not write the install log: {}", err);
+        }
+        result
+    }
+}
+
+// Copy the log of the live ISO into the new system mounted on the target, so a failed boot can still be diagnosed from the installed system. It always goes to the default log directory there, wherever it was written on the live ISO. A resumed run copies it again, so the log in the new system includes the run that finished the install.
+pub fn copy_to_target(plan: &Plan, runner: &mut dyn Runner, section: &str) -> Result<()> {
+    let target = PathBuf::from(plan.in_target(LOG_DIR));
+    let log = Log::new(Path::new(&plan.stages.log));
+
+    runner.perform_always(
+        &format!("{}/log", section),
+        &format!("Copy the install log to {}", target.display()),
+        &mut || {
+            fs::create_dir_all(&target)?;
+            if log.text.exists() {
+                fs::copy(&log.text, target.join(LIVE_LOG))?;
+            }
+            if log.json.exists() {
+                fs::copy(&log.json, target.join(LIVE_JSON_LOG))?;
+            }
+            Ok(())
+        },
+    )
+}
@@ -1,48 +1,147 @@
 mod chroot;
+mod cli;
+mod disks;
+mod error;
+mod finish;
+mod gpt;
+mod journal;
+mod log;
+mod plan;
+mod preflight;
+mod properties;
+mod runner;
 mod user;
 mod zfs;
 
+use cli::{Cli, Command, Options};
+use error::{InstallError, Result};
+use journal::{Journal, JournalRunner};
+use log::{Log, LogRunner, LOG_DIR};
+use plan::Plan;
+use runner::{ProcessRunner, RecordingRunner, Runner};
 use std::env;
 use std::io;
+use std::path::Path;
 
 fn main() {
-    // Collect command line arguments into a vector of strings.
-    let args: Vec<String> = env::args().collect();
-
-    // If any arguments are provided, iterate through them, skipping the first one (the executable name).
-    if args.len() > 1 {
-        for arg in args.iter().skip(1) {
-            // Call the appropriate function based on the argument provided.
-            match arg.as_str() {
-                "--zfs" => zfs::zfs(),
-                "--chroot" => chroot::chroot(),
-                "--user" => user::user(),
-                _ => print!("Invalid Flag Passed"),
+    // Run the installer and turn any error into a summary of the step that failed and the exit code for its kind. Usage errors aren't a failed install, they only say what was wrong with the command line.
+    if let Err(err) = run() {
+        match err {
+            InstallError::Usage(_) => eprintln!("install: {}", err),
+            _ => eprintln!("Install failed: {}", err),
+        }
+        std::process::exit(err.exit_code());
+    }
+}
+
+fn run() -> Result<()> {
+    // Parse the command line, everything after the executable name.
+    let args: Vec<String> = env::args().skip(1).collect();
+    let Cli { command, options } = cli::parse(&args)?;
+
+    match command {
+        Command::Help => {
+            println!("{}", cli::HELP);
+            return Ok(());
+        }
+        Command::Version => {
+            println!("install {}", env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    // Load the install plan if one is passed with --plan, otherwise use the defaults and prompt for the rest. The target and log directory from the command line override the ones in the plan.
+    let mut plan = match &options.plan {
+        Some(path) => Plan::load(Path::new(path))?,
+        None => Plan::default(),
+    };
+    if let Some(target) = &options.target {
+        plan.stages.target = target.clone();
+    }
+    if let Some(log) = &options.log {
+        plan.stages.log = log.clone();
+    }
+
+    // A normal user, like the one running the user stage, can't write to /var/log, so their log goes to their state directory unless it was set somewhere else.
+    if plan.stages.log == LOG_DIR && users::get_effective_uid() != 0 {
+        if let Some(dir) = journal::user_state_dir() {
+            plan.stages.log = dir.join("log").to_string_lossy().to_string();
+        }
+    }
+
+    if command == Command::PlanValidate {
+        return match &options.plan {
+            Some(path) => {
+                println!("The install plan {} is valid", path);
+                Ok(())
             }
+            None => Err(cli::usage(
+                "plan validate needs the plan to check, pass it with --plan",
+            )),
+        };
+    }
+
+    // The all command is the disk stage continuing into the chroot and finish stages.
+    if command == Command::All {
+        plan.stages.chroot = true;
+        plan.stages.finish = true;
+    }
+
+    // Every command the stages run goes through the process runner keeping the journal and the log, or the recording runner for a dry run. With --resume the steps that completed in an earlier run are skipped.
+    let mut runner: Box<dyn Runner> = if options.dry_run {
+        let mut recorder = RecordingRunner::echoing();
+        // The user stage asks whoami for the user it runs as, answer it with the user running the dry run.
+        if let Some(username) = users::get_current_username() {
+            recorder.respond("whoami", &username.to_string_lossy());
         }
+        Box::new(recorder)
     } else {
-        // If no arguments are provided, call the no_flag_passed function to handle user input.
-        no_flag_passed();
+        let journal_path = journal::journal_path();
+        let journal = if options.resume {
+            Journal::resume(&journal_path)?
+        } else {
+            Journal::start(&journal_path)
+        };
+        let log = Log::new(Path::new(&plan.stages.log));
+        Box::new(JournalRunner::new(
+            LogRunner::new(ProcessRunner, log),
+            journal,
+        ))
+    };
+    let runner = runner.as_mut();
+
+    // With --yes the drives are wiped without typing their serials, for unattended installs from a plan.
+    match command {
+        Command::Menu => no_command_passed(&plan, runner, &options),
+        Command::Disk | Command::All => zfs::zfs(&plan, runner, options.yes, options.resume),
+        Command::Chroot => chroot::chroot(&plan, runner),
+        Command::User => user::user(&plan, runner),
+        Command::Finish => finish::finish(&plan, runner),
+        Command::PlanValidate | Command::Help | Command::Version => Ok(()),
     }
 }
 
-// Function to prompt the user for input when no flag is provided.
-fn no_flag_passed() {
+// Function to prompt the user for input when no command is provided.
+fn no_command_passed(plan: &Plan, runner: &mut dyn Runner, options: &Options) -> Result<()> {
     println!("Choose an option:");
-    println!("1. ZFS");
+    println!("1. Disk");
     println!("2. Chroot");
     println!("3. User");
+    println!("4. Finish");
 
     // Read user input and call the appropriate function based on the user's choice.
     let mut choice = String::new();
-    io::stdin()
-        .read_line(&mut choice)
-        .expect("Failed to read line");
+    io::stdin().read_line(&mut choice)?;
 
     match choice.trim() {
-        "1" => zfs::zfs(),
-        "2" => chroot::chroot(),
-        "3" => user::user(),
-        _ => println!("Invalid choice"),
+        "1" => zfs::zfs(plan, runner, options.yes, options.resume),
+        "2" => chroot::chroot(plan, runner),
+        "3" => user::user(plan, runner),
+        "4" => finish::finish(plan, runner),
+        _ => Err(InstallError::InvalidInput(format!(
+            "Invalid choice: {}",
+            choice.trim()
+        ))),
     }
 }
@@ -0,0 +1,1025 @@
+use crate::error::{InstallError, Result};
+use crate::log::LOG_DIR;
+use crate::properties::{check_dataset_property, check_pool_property, size_bytes};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+// The install plan describes everything the three stages need so an install can run without answering prompts. Every field has a default matching the values the installer used before plans existed, so an empty plan behaves exactly like running without one. Fields left as None are asked for interactively.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Plan {
+    pub disk: DiskPlan,
+    pub partitions: PartitionPlan,
+    pub pool: PoolPlan,
+    pub datasets: Vec<DatasetPlan>,
+    pub encryption: EncryptionPlan,
+    pub packages: PackagePlan,
+    pub users: Vec<UserPlan>,
+    pub root: RootPlan,
+    pub services: ServicePlan,
+    pub dotfiles: DotfilesPlan,
+    pub system: SystemPlan,
+    pub stages: StagePlan,
+}
+
+// The disks the ZFS stage installs to, given as their names in /dev/disk/by-id. Every disk is partitioned the same way and the ZFS partitions make up the pool.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskPlan {
+    pub drives: Vec<String>,
+    pub wipe: WipeStrategy,
+    pub image: Option<String>,
+    pub image_size: String,
+}
+
+// How the drives are wiped before they are partitioned. Discard and secure erase fall back to zap on drives that don't support them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WipeStrategy {
+    // Discard every block with blkdiscard, fast on SSDs
+    #[default]
+    Discard,
+    // Remove all filesystem, RAID and partition table signatures with wipefs
+    Zap,
+    // Overwrite the first and last MiB of the drive with zeroes
+    Zero,
+    // Erase the whole drive in its firmware, with nvme format on NVMe and the ATA security erase on SATA
+    SecureErase,
+    // Leave the data on the drive, only a new partition table is written
+    None,
+}
+
+// The partitions created on every drive, the ZFS partition gets what is left apart from the reserved space at the end of the drive. Sizes are written the way zfs writes them, like 512M or 8G.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PartitionPlan {
+    pub esp_size: String,
+    pub bios_boot: bool,
+    pub xbootldr_size: Option<String>,
+    pub swap_size: Option<String>,
+    pub encrypt_swap: bool,
+    pub reserved: Option<String>,
+}
+
+// The kinds of partition the installer creates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PartitionKind {
+    BiosBoot,
+    Esp,
+    Xbootldr,
+    Swap,
+    Zfs,
+}
+
+// The pool name, how its disks are combined and the dataset (relative to the pool) that is mounted as / and booted from. The topology is asked for when several drives are used and none is given. The properties are merged over the defaults, ashift is detected from the drives unless it's given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolPlan {
+    pub name: String,
+    pub topology: Option<Topology>,
+    pub root_dataset: String,
+    #[serde(deserialize_with = "property_map")]
+    pub properties: BTreeMap<String, String>,
+    #[serde(deserialize_with = "property_map")]
+    pub filesystem_properties: BTreeMap<String, String>,
+}
+
+// How the disks of the pool are combined into a single vdev.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Topology {
+    Stripe,
+    Mirror,
+    Raidz1,
+    Raidz2,
+    Raidz3,
+}
+
+// A single dataset created under the pool, the name is relative to the pool. Any other ZFS properties like recordsize, compression, quota or com.sun:auto-snapshot go in properties.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DatasetPlan {
+    pub name: String,
+    pub mountpoint: Option<String>,
+    pub canmount: Option<String>,
+    #[serde(default, deserialize_with = "property_map")]
+    pub properties: BTreeMap<String, String>,
+}
+
+// Native ZFS encryption of the whole pool with aes-256-gcm. With a passphrase the initramfs asks for it on every boot, with a keyfile the 32 byte raw key at that path on the live system is copied into the installed system and its initramfs.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionPlan {
+    pub enabled: bool,
+    pub key: KeySource,
+    pub keyfile: Option<String>,
+}
+
+// Where the encryption key comes from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    #[default]
+    Passphrase,
+    Keyfile,
+}
+
+// The packages installed by pacstrap, by pacman inside the chroot and by yay in the user stage.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PackagePlan {
+    pub base: Vec<String>,
+    pub system: Vec<String>,
+    pub aur: Vec<String>,
+}
+
+// A user created in the chroot stage, the password is asked for when it is not given.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserPlan {
+    pub name: String,
+    pub password: Option<String>,
+    #[serde(default = "default_shell")]
+    pub shell: String,
+    #[serde(default = "default_groups")]
+    pub groups: Vec<String>,
+}
+
+// What the chroot stage does with the root account: leave it as pacstrap created it, give it a password (asked for when it is not given) or lock it so root can only be reached through sudo.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RootPlan {
+    pub account: RootAccount,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootAccount {
+    #[default]
+    Unchanged,
+    Password,
+    Locked,
+}
+
+// The extra services enabled in the chroot stage next to the ZFS ones (system), by the user stage through sudo (desktop) and as the user themselves (user).
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServicePlan {
+    pub system: Vec<String>,
+    pub desktop: Vec<String>,
+    pub user: Vec<String>,
+}
+
+// The dotfiles repository as "username/repository_name" on github and whether to apply Stetsed's personal configuration.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DotfilesPlan {
+    pub repository: Option<String>,
+    pub stetsed_extras: Option<bool>,
+}
+
+// The CPU platform used to pick the microcode package and the timezone set in the user stage.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SystemPlan {
+    pub platform: Option<String>,
+    pub timezone: String,
+}
+
+// The stages the ZFS stage continues into, so a single run ends with an installed system: the chroot stage through arch-chroot with the answers asked for up front, and then the finish stage. The installer copies itself to the installer path in the new system to run the later stages from. The new system is mounted on the target, and the stages log into the log directory.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StagePlan {
+    pub chroot: bool,
+    pub finish: bool,
+    pub installer: String,
+    pub target: String,
+    pub log: String,
+}
+
+impl Default for StagePlan {
+    fn default() -> Self {
+        StagePlan {
+            chroot: false,
+            finish: false,
+            installer: "/install".to_string(),
+            target: "/mnt".to_string(),
+            log: LOG_DIR.to_string(),
+        }
+    }
+}
+
+impl Default for Plan {
+    fn default() -> Self {
+        Plan {
+            disk: DiskPlan::default(),
+            partitions: PartitionPlan::default(),
+            pool: PoolPlan::default(),
+            datasets: default_datasets(),
+            encryption: EncryptionPlan::default(),
+            packages: PackagePlan::default(),
+            users: Vec::new(),
+            root: RootPlan::default(),
+            services: ServicePlan::default(),
+            dotfiles: DotfilesPlan::default(),
+            system: SystemPlan::default(),
+            stages: StagePlan::default(),
+        }
+    }
+}
+
+impl Default for PoolPlan {
+    fn default() -> Self {
+        PoolPlan {
+            name: "zroot".to_string(),
+            topology: None,
+            root_dataset: "ROOT/default".to_string(),
+            properties: BTreeMap::new(),
+            filesystem_properties: BTreeMap::new(),
+        }
+    }
+}
+
+impl Default for PackagePlan {
+    fn default() -> Self {
+        PackagePlan {
+            base: strings(&[
+                "base",
+                "base-devel",
+                "linux",
+                "linux-firmware",
+                "neovim",
+                "networkmanager",
+            ]),
+            system: strings(&[
+                "nfs-utils",
+                "linux-headers",
+                "zfs-dkms",
+                "openssh",
+                "networkmanager",
+                "fish",
+                "git",
+            ]),
+            aur: strings(&[
+                "imagemagick",
+                "rust-analyzer",
+                "kitty",
+                "ripgrep",
+                "unzip",
+                "bat",
+                "pavucontrol",
+                "pipewire-pulse",
+                "dunst",
+                "bluedevil",
+                "bluez-utils",
+                "brightnessctl",
+                "grimblast-git",
+                "neovim",
+                "network-manager-applet",
+                "rofi-lbonn-wayland-git",
+                "starship",
+                "thunar",
+                "thunar-archive-plugin",
+                "thunar-volman",
+                "webcord-bin",
+                "wl-clipboard",
+                "librewolf-bin",
+                "neofetch",
+                "swaybg",
+                "waybar-hyprland-git",
+                "btop",
+                "tldr",
+                "swaylock-effects",
+                "obsidian",
+                "fish",
+                "hyprland",
+                "npm",
+                "xdg-desktop-portal-hyprland-git",
+                "exa",
+                "noto-fonts-emoji",
+                "qt5-wayland",
+                "qt6-wayland",
+                "blueman",
+                "swappy",
+                "playerctl",
+                "wlogout",
+                "sddm-git",
+                "nano",
+                "ttf-jetbrains-mono-nerd",
+                "lazygit",
+                "swayidle",
+            ]),
+        }
+    }
+}
+
+impl Default for ServicePlan {
+    fn default() -> Self {
+        ServicePlan {
+            system: strings(&["NetworkManager"]),
+            desktop: strings(&["bluetooth", "sddm"]),
+            user: strings(&["pipewire", "pipewire-pulse"]),
+        }
+    }
+}
+
+impl Default for SystemPlan {
+    fn default() -> Self {
+        SystemPlan {
+            platform: None,
+            timezone: "Europe/Amsterdam".to_string(),
+        }
+    }
+}
+
+impl Default for DiskPlan {
+    fn default() -> Self {
+        DiskPlan {
+            drives: Vec::new(),
+            wipe: WipeStrategy::default(),
+            image: None,
+            image_size: "32G".to_string(),
+        }
+    }
+}
+
+impl Default for PartitionPlan {
+    fn default() -> Self {
+        PartitionPlan {
+            esp_size: "512M".to_string(),
+            bios_boot: false,
+            xbootldr_size: None,
+            swap_size: None,
+            encrypt_swap: false,
+            reserved: None,
+        }
+    }
+}
+
+impl PartitionPlan {
+    // The partitions in the order they are created, the partition number is the position in the list plus one.
+    pub fn layout(&self) -> Vec<PartitionKind> {
+        let mut layout = Vec::new();
+        if self.bios_boot {
+            layout.push(PartitionKind::BiosBoot);
+        }
+        layout.push(PartitionKind::Esp);
+        if self.xbootldr_size.is_some() {
+            layout.push(PartitionKind::Xbootldr);
+        }
+        if self.swap_size.is_some() {
+            layout.push(PartitionKind::Swap);
+        }
+        layout.push(PartitionKind::Zfs);
+        layout
+    }
+
+    pub fn number(&self, kind: PartitionKind) -> Option<usize> {
+        self.layout()
+            .iter()
+            .position(|partition| *partition == kind)
+            .map(|index| index + 1)
+    }
+
+    // Where the installed system mounts the ESP. With an XBOOTLDR partition that one is /boot, holding the kernels and boot entries, and the ESP moves to /efi.
+    pub fn esp_mountpoint(&self) -> &'static str {
+        if self.xbootldr_size.is_some() {
+            "/efi"
+        } else {
+            "/boot"
+        }
+    }
+}
+
+impl WipeStrategy {
+    // The programs the strategy needs, including the ones it falls back to. Secure erase checks for nvme and hdparm when it wipes a drive and falls back when they are missing.
+    pub fn tools(&self) -> &'static [&'static str] {
+        match self {
+            WipeStrategy::Discard => &["blkdiscard", "wipefs"],
+            WipeStrategy::Zap => &["wipefs"],
+            WipeStrategy::Zero => &["dd"],
+            WipeStrategy::SecureErase => &["wipefs"],
+            WipeStrategy::None => &[],
+        }
+    }
+}
+
+impl Topology {
+    pub const ALL: [Topology; 5] = [
+        Topology::Stripe,
+        Topology::Mirror,
+        Topology::Raidz1,
+        Topology::Raidz2,
+        Topology::Raidz3,
+    ];
+
+    // The name used in the plan and at the prompt.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Topology::Stripe => "stripe",
+            Topology::Mirror => "mirror",
+            Topology::Raidz1 => "raidz1",
+            Topology::Raidz2 => "raidz2",
+            Topology::Raidz3 => "raidz3",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Topology> {
+        Topology::ALL
+            .into_iter()
+            .find(|topology| topology.name() == name)
+    }
+
+    // The number of disks zpool needs for the topology, a raidz needs more disks than it has parity.
+    pub fn min_drives(&self) -> usize {
+        match self {
+            Topology::Stripe => 1,
+            Topology::Mirror | Topology::Raidz1 => 2,
+            Topology::Raidz2 => 3,
+            Topology::Raidz3 => 4,
+        }
+    }
+
+    // The vdev type passed to zpool create in front of the disks, a stripe lists the disks on their own.
+    pub fn vdev(&self) -> Option<&'static str> {
+        match self {
+            Topology::Stripe => None,
+            topology => Some(topology.name()),
+        }
+    }
+}
+
+impl Plan {
+    // Read a plan from a TOML file and validate it, all problems found are reported together in the returned error.
+    pub fn load(path: &Path) -> Result<Plan> {
+        let contents = fs::read_to_string(path).map_err(|err| {
+            InstallError::InvalidPlan(format!("Could not read {}: {}", path.display(), err))
+        })?;
+        let plan = Plan::parse(&contents).map_err(|err| match err {
+            InstallError::InvalidPlan(message) => {
+                InstallError::InvalidPlan(format!("{}: {}", path.display(), message))
+            }
+            err => err,
+        })?;
+
+        let problems = plan.validate();
+        if !problems.is_empty() {
+            return Err(InstallError::InvalidPlan(format!(
+                "{} has problems:\n  {}",
+                path.display(),
+                problems.join("\n  ")
+            )));
+        }
+
+        Ok(plan)
+    }
+
+    // Parse a plan from TOML, sections that are left out keep their defaults.
+    pub fn parse(contents: &str) -> Result<Plan> {
+        toml::from_str(contents).map_err(|e| InstallError::InvalidPlan(e.to_string()))
+    }
+
+    // Check the plan for values that would make a stage fail halfway, returning a description of every problem found.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for (index, drive) in self.disk.drives.iter().enumerate() {
+            if drive.is_empty() || drive.contains('/') {
+                problems.push(format!(
+                    "disk.drives '{}' must be a name from /dev/disk/by-id",
+                    drive
+                ));
+            }
+            if self.disk.drives[..index].contains(drive) {
+                problems.push(format!("disk.drives '{}' is listed twice", drive));
+            }
+        }
+
+        // An image is installed to instead of drives, as a single disk
+        if let Some(image) = &self.disk.image {
+            if !image.starts_with('/') {
+                problems.push(format!("disk.image '{}' must be an absolute path", image));
+            }
+            if !self.disk.drives.is_empty() {
+                problems.push("disk.image and disk.drives can't be used together".to_string());
+            }
+            if self
+                .pool
+                .topology
+                .is_some_and(|topology| topology != Topology::Stripe)
+            {
+                problems
+                    .push("An image is a single disk, pool.topology has to be stripe".to_string());
+            }
+            if size_bytes(&self.disk.image_size).is_none_or(|bytes| bytes < 16 << 30) {
+                problems.push(format!(
+                    "disk.image_size '{}' is not a size of at least 16G",
+                    self.disk.image_size
+                ));
+            }
+        }
+
+        if let Some(topology) = self.pool.topology {
+            if !self.disk.drives.is_empty() && self.disk.drives.len() < topology.min_drives() {
+                problems.push(format!(
+                    "pool.topology {} needs at least {} drives, disk.drives has {}",
+                    topology.name(),
+                    topology.min_drives(),
+                    self.disk.drives.len()
+                ));
+            }
+        }
+
+        // FAT32 needs a partition of at least 32 MiB, which both the ESP and the XBOOTLDR partition are formatted with
+        let mut fat_sizes = vec![("partitions.esp_size", &self.partitions.esp_size)];
+        if let Some(size) = &self.partitions.xbootldr_size {
+            fat_sizes.push(("partitions.xbootldr_size", size));
+        }
+        for (field, size) in fat_sizes {
+            match size_bytes(size) {
+                Some(bytes) if bytes >= 32 << 20 => {}
+                Some(_) => problems.push(format!("{} '{}' must be at least 32M", field, size)),
+                None => problems.push(format!("{} '{}' is not a size like 512M", field, size)),
+            }
+        }
+        let other_sizes = [
+            ("partitions.swap_size", &self.partitions.swap_size),
+            ("partitions.reserved", &self.partitions.reserved),
+        ];
+        for (field, size) in other_sizes {
+            if let Some(size) = size {
+                if size_bytes(size).is_none_or(|bytes| bytes < 1 << 20) {
+                    problems.push(format!("{} '{}' is not a size of at least 1M", field, size));
+                }
+            }
+        }
+        if self.partitions.encrypt_swap && self.partitions.swap_size.is_none() {
+            problems.push("partitions.encrypt_swap needs partitions.swap_size".to_string());
+        }
+
+        if !is_valid_zfs_name(&self.pool.name) || self.pool.name.contains('/') {
+            problems.push(format!(
+                "pool.name '{}' is not a valid pool name",
+                self.pool.name
+            ));
+        }
+
+        for (property, value) in &self.pool.properties {
+            if let Some(problem) = check_pool_property(property, value) {
+                problems.push(format!("pool.properties: {}", problem));
+            }
+        }
+
+        for (property, value) in &self.pool.filesystem_properties {
+            if let Some(problem) = check_dataset_property(property, value) {
+                problems.push(format!("pool.filesystem_properties: {}", problem));
+            }
+        }
+
+        // Every dataset needs a unique valid name and its parent must be declared as well, they are created parents first whatever order they are declared in.
+        let declared: Vec<&str> = self
+            .datasets
+            .iter()
+            .map(|dataset| dataset.name.as_str())
+            .collect();
+        for (index, dataset) in self.datasets.iter().enumerate() {
+            if !is_valid_zfs_name(&dataset.name) {
+                problems.push(format!(
+                    "dataset '{}' is not a valid dataset name",
+                    dataset.name
+                ));
+            }
+            if declared[..index].contains(&dataset.name.as_str()) {
+                problems.push(format!("dataset '{}' is declared twice", dataset.name));
+            }
+            if let Some((parent, _)) = dataset.name.rsplit_once('/') {
+                if !declared.contains(&parent) {
+                    problems.push(format!(
+                        "dataset '{}' needs its parent '{}' to be declared too",
+                        dataset.name, parent
+                    ));
+                }
+            }
+            if let Some(mountpoint) = &dataset.mountpoint {
+                if mountpoint != "none" && mountpoint != "legacy" && !mountpoint.starts_with('/') {
+                    problems.push(format!(
+                        "dataset '{}' has mountpoint '{}', expected an absolute path, none or legacy",
+                        dataset.name, mountpoint
+                    ));
+                }
+            }
+            if let Some(canmount) = &dataset.canmount {
+                if !["on", "off", "noauto"].contains(&canmount.as_str()) {
+                    problems.push(format!(
+                        "dataset '{}' has canmount '{}', expected on, off or noauto",
+                        dataset.name, canmount
+                    ));
+                }
+            }
+            for (property, value) in &dataset.properties {
+                if let Some(problem) = check_dataset_property(property, value) {
+                    problems.push(format!("dataset '{}': {}", dataset.name, problem));
+                }
+            }
+        }
+
+        if !declared.contains(&self.pool.root_dataset.as_str()) {
+            problems.push(format!(
+                "pool.root_dataset '{}' is not one of the declared datasets",
+                self.pool.root_dataset
+            ));
+        }
+
+        if self.encryption.enabled {
+            match (self.encryption.key, &self.encryption.keyfile) {
+                (KeySource::Keyfile, None) => problems.push(
+                    "encryption.keyfile is needed when encryption.key is keyfile".to_string(),
+                ),
+                (KeySource::Keyfile, Some(keyfile)) if !keyfile.starts_with('/') => problems.push(
+                    format!("encryption.keyfile '{}' must be an absolute path", keyfile),
+                ),
+                (KeySource::Passphrase, Some(_)) => problems.push(
+                    "encryption.keyfile is only used when encryption.key is keyfile".to_string(),
+                ),
+                _ => {}
+            }
+        }
+
+        if self.packages.base.is_empty() {
+            problems.push("packages.base must not be empty".to_string());
+        }
+
+        let mut names: Vec<&str> = Vec::new();
+        for user in &self.users {
+            if !is_valid_username(&user.name) {
+                problems.push(format!("user '{}' is not a valid username", user.name));
+            }
+            if names.contains(&user.name.as_str()) {
+                problems.push(format!("user '{}' is declared twice", user.name));
+            }
+            if !user.shell.starts_with('/') {
+                problems.push(format!(
+                    "user '{}' has shell '{}', expected an absolute path",
+                    user.name, user.shell
+                ));
+            }
+            if let Some(problem) = user
+                .password
+                .as_deref()
+                .and_then(|password| check_password(&user.name, password))
+            {
+                problems.push(format!("user '{}': {}", user.name, problem));
+            }
+            names.push(&user.name);
+        }
+
+        match (self.root.account, &self.root.password) {
+            (RootAccount::Password, Some(password)) => {
+                if let Some(problem) = check_password("root", password) {
+                    problems.push(format!("root.password: {}", problem));
+                }
+            }
+            (_, Some(_)) => problems
+                .push("root.password is only used when root.account is password".to_string()),
+            _ => {}
+        }
+
+        if let Some(repository) = &self.dotfiles.repository {
+            if repository
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .count()
+                != 2
+            {
+                problems.push(format!(
+                    "dotfiles.repository '{}' must look like username/repository_name",
+                    repository
+                ));
+            }
+        }
+
+        if !self.stages.installer.starts_with('/') || self.stages.installer.ends_with('/') {
+            problems.push(format!(
+                "stages.installer '{}' must be an absolute path to a file",
+                self.stages.installer
+            ));
+        }
+
+        // The target can't be / itself, that is the live system the installer runs on
+        if !self.stages.target.starts_with('/') || self.stages.target.trim_matches('/').is_empty() {
+            problems.push(format!(
+                "stages.target '{}' must be an absolute path to a directory other than /",
+                self.stages.target
+            ));
+        }
+
+        if !self.stages.log.starts_with('/') {
+            problems.push(format!(
+                "stages.log '{}' must be an absolute path",
+                self.stages.log
+            ));
+        }
+
+        if self.stages.finish && !self.stages.chroot {
+            problems.push(
+                "stages.finish needs stages.chroot, the system can't boot before the chroot stage ran"
+                    .to_string(),
+            );
+        }
+
+        if let Some(platform) = &self.system.platform {
+            if !is_valid_platform(platform) {
+                problems.push(format!(
+                    "system.platform '{}' must be intel or amd",
+                    platform
+                ));
+            }
+        }
+
+        if self.system.timezone.is_empty()
+            || self.system.timezone.starts_with('/')
+            || self.system.timezone.contains("..")
+        {
+            problems.push(format!(
+                "system.timezone '{}' must look like Region/City",
+                self.system.timezone
+            ));
+        }
+
+        problems
+    }
+
+    // The path in the new system mounted on the target, for example /mnt/etc/fstab for /etc/fstab.
+    pub fn in_target(&self, path: &str) -> String {
+        format!("{}{}", self.stages.target.trim_end_matches('/'), path)
+    }
+
+    // Where the installed system finds the key of an encrypted pool unlocked with a keyfile.
+    pub fn installed_keyfile(&self) -> String {
+        format!("/etc/zfs/{}.key", self.pool.name)
+    }
+
+    // The pool properties for zpool create, the ashift detected from the drives is used unless the plan sets one.
+    pub fn pool_properties(&self, detected_ashift: u32) -> BTreeMap<String, String> {
+        let mut properties = BTreeMap::from([("ashift".to_string(), detected_ashift.to_string())]);
+        properties.extend(self.pool.properties.clone());
+        properties
+    }
+
+    // The properties of the root of the pool that every dataset inherits, the plan's properties override the defaults the installer always used.
+    pub fn filesystem_properties(&self) -> BTreeMap<String, String> {
+        let mut properties: BTreeMap<String, String> = [
+            ("acltype", "posixacl"),
+            ("compression", "on"),
+            ("atime", "off"),
+            ("xattr", "sa"),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+        properties.extend(self.pool.filesystem_properties.clone());
+        properties
+    }
+
+    // The datasets in the order they can be created, every parent before its children and otherwise in the order they are declared.
+    pub fn datasets_in_order(&self) -> Vec<&DatasetPlan> {
+        let mut datasets: Vec<&DatasetPlan> = self.datasets.iter().collect();
+        datasets.sort_by_key(|dataset| dataset.name.matches('/').count());
+        datasets
+    }
+
+    // The full name of the dataset mounted as /, for example zroot/ROOT/default.
+    pub fn root_dataset(&self) -> String {
+        format!("{}/{}", self.pool.name, self.pool.root_dataset)
+    }
+}
+
+// The dataset tree the installer has always created: a boot environment under ROOT and home under data.
+fn default_datasets() -> Vec<DatasetPlan> {
+    vec![
+        DatasetPlan {
+            name: "ROOT".to_string(),
+            mountpoint: Some("none".to_string()),
+            canmount: Some("off".to_string()),
+            properties: BTreeMap::new(),
+        },
+        DatasetPlan {
+            name: "ROOT/default".to_string(),
+            mountpoint: Some("/".to_string()),
+            canmount: Some("noauto".to_string()),
+            properties: BTreeMap::new(),
+        },
+        DatasetPlan {
+            name: "data".to_string(),
+            mountpoint: Some("none".to_string()),
+            canmount: None,
+            properties: BTreeMap::new(),
+        },
+        DatasetPlan {
+            name: "data/home".to_string(),
+            mountpoint: Some("/home".to_string()),
+            canmount: None,
+            properties: BTreeMap::new(),
+        },
+    ]
+}
+
+// The shortest password the installer accepts for a user or root.
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// Check a password against the policy, returning what is wrong with it.
+pub fn check_password(username: &str, password: &str) -> Option<String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Some(format!(
+            "the password must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        ));
+    }
+    if password.eq_ignore_ascii_case(username) {
+        return Some("the password must not be the username".to_string());
+    }
+    // chpasswd reads one user per line, a line break would start another one
+    if password.contains(['\n', '\r']) {
+        return Some("the password must not contain a line break".to_string());
+    }
+    None
+}
+
+// Property values can be written as TOML strings, numbers or booleans, zfs takes all of them as text.
+fn property_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error> {
+    let values = BTreeMap::<String, toml::Value>::deserialize(deserializer)?;
+    values
+        .into_iter()
+        .map(|(name, value)| match value {
+            toml::Value::String(value) => Ok((name, value)),
+            toml::Value::Integer(value) => Ok((name, value.to_string())),
+            toml::Value::Boolean(value) => Ok((name, value.to_string())),
+            _ => Err(serde::de::Error::custom(format!(
+                "property {} must be a string, number or boolean",
+                name
+            ))),
+        })
+        .collect()
+}
+
+fn default_shell() -> String {
+    "/usr/bin/fish".to_string()
+}
+
+fn default_groups() -> Vec<String> {
+    strings(&["wheel"])
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
+
+// ZFS names may contain alphanumerics, '_', '-', ':', '.' and '/' as a separator between non-empty components.
+fn is_valid_zfs_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.split('/').all(|component| {
+            !component.is_empty()
+                && component
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-:.".contains(c))
+        })
+}
+
+// Usernames follow the useradd defaults: a lowercase letter or '_' followed by lowercase letters, digits, '_' or '-'.
+pub fn is_valid_username(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_lowercase() || first == '_' => {
+            chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-')
+        }
+        _ => false,
+    }
+}
+
+// The platforms there is a microcode package for.
+pub fn is_valid_platform(platform: &str) -> bool {
+    platform == "intel" || platform == "amd"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn an_empty_plan_uses_the_defaults() {
+        let plan = Plan::parse("").unwrap();
+
+        assert!(plan.validate().is_empty());
+        assert_eq!(plan.disk.wipe, WipeStrategy::Discard);
+        assert_eq!(plan.disk.image_size, "32G");
+        assert_eq!(plan.partitions.esp_size, "512M");
+        assert_eq!(
+            plan.partitions.layout(),
+            [PartitionKind::Esp, PartitionKind::Zfs]
+        );
+        assert_eq!(plan.root_dataset(), "zroot/ROOT/default");
+        assert_eq!(plan.stages.target, "/mnt");
+        assert_eq!(plan.stages.log, LOG_DIR);
+        assert!(plan.users.is_empty());
+        assert!(plan.system.platform.is_none());
+    }
+
+    #[test]
+    fn sections_that_are_given_keep_the_defaults_of_their_other_fields() {
+        let plan = Plan::parse(
+            r#"
+            [disk]
+            drives = ["ata-DISK_A"]
+
+            [[users]]
+            name = "alice"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(plan.disk.drives, ["ata-DISK_A"]);
+        assert_eq!(plan.disk.wipe, WipeStrategy::Discard);
+        assert_eq!(plan.users[0].shell, "/usr/bin/fish");
+        assert_eq!(plan.users[0].groups, ["wheel"]);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for contents in [
+            "hostname = \"box\"",
+            "[disk]\ndrive = \"ata-DISK_A\"",
+            "[pool]\nnmae = \"tank\"",
+            "[[users]]\nname = \"alice\"\nshel = \"/bin/bash\"",
+        ] {
+            let err = Plan::parse(contents).unwrap_err();
+            assert!(
+                matches!(&err, InstallError::InvalidPlan(message) if message.contains("unknown field")),
+                "{}: {}",
+                contents,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn every_problem_is_reported_together() {
+        let plan = Plan::parse(
+            r#"
+            [disk]
+            drives = ["/dev/sda", "ata-DISK_A", "ata-DISK_A"]
+
+            [partitions]
+            esp_size = "lots"
+
+            [pool]
+            name = "my pool"
+
+            [packages]
+            base = []
+
+            [[users]]
+            name = "Alice"
+            password = "short"
+
+            [system]
+            platform = "arm"
+            "#,
+        )
+        .unwrap();
+
+        let problems = plan.validate();
+        for expected in [
+            "disk.drives '/dev/sda' must be a name from /dev/disk/by-id",
+            "disk.drives 'ata-DISK_A' is listed twice",
+            "partitions.esp_size 'lots' is not a size like 512M",
+            "pool.name 'my pool' is not a valid pool name",
+            "packages.base must not be empty",
+            "user 'Alice' is not a valid username",
+            "user 'Alice': the password must be at least 8 characters",
+            "system.platform 'arm' must be intel or amd",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem == expected),
+                "missing '{}' in {:#?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn loading_a_missing_plan_names_the_file() {
+        let err = Plan::load(Path::new("/nonexistent/host.toml")).unwrap_err();
+        assert!(matches!(err, InstallError::InvalidPlan(_)));
+        assert!(
+            err.to_string().contains("/nonexistent/host.toml"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,144 @@
+use crate::disks;
+use crate::error::{InstallError, Result};
+use crate::plan::Plan;
+use crate::runner::Runner;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+// The programs the ZFS stage runs, zpool and zfs only once ZFS has been loaded into the live environment.
+const REQUIRED_TOOLS: [&str; 5] = ["blockdev", "udevadm", "mkfs.vfat", "pacstrap", "genfstab"];
+const ZFS_TOOLS: [&str; 2] = ["zpool", "zfs"];
+
+// Building zfs-dkms and running pacstrap from the live ISO needs a fair amount of memory.
+const MIN_MEMORY_MIB: u64 = 2048;
+
+// The smallest disk worth installing to, the base system with the packages from the user stage takes most of this.
+const MIN_DISK_GIB: u64 = 16;
+
+// Check that the system is ready for the ZFS stage to wipe the drives, reporting every problem together. This runs before the stage changes anything, so ZFS itself isn't installed yet and is checked by preflight_zfs once it is. A dry run only prints the problems, since it's usually not run from the live ISO. When resuming, the datasets and partitions the earlier run mounted on the target are expected to still be there.
+pub fn preflight(runner: &dyn Runner, plan: &Plan, drives: &[String], resume: bool) -> Result<()> {
+    let mut problems = Vec::new();
+
+    if !Path::new("/sys/firmware/efi").exists() {
+        problems.push(
+            "The system is not booted in UEFI mode (/sys/firmware/efi is missing)".to_string(),
+        );
+    }
+
+    if users::get_effective_uid() != 0 {
+        problems.push("The installer is not running as root".to_string());
+    }
+
+    // Continuing into the chroot stage needs arch-chroot on top of the tools of the stage and the wipe strategy
+    let chroot_tools: &[&str] = if plan.stages.chroot {
+        &["arch-chroot"]
+    } else {
+        &[]
+    };
+    for tool in REQUIRED_TOOLS
+        .iter()
+        .chain(plan.disk.wipe.tools())
+        .chain(chroot_tools)
+    {
+        if !is_installed(tool) {
+            problems.push(format!("Required tool '{}' is not installed", tool));
+        }
+    }
+
+    match memory_mib() {
+        Some(memory) if memory < MIN_MEMORY_MIB => problems.push(format!(
+            "Only {} MiB of memory is available, at least {} MiB is needed",
+            memory, MIN_MEMORY_MIB
+        )),
+        Some(_) => {}
+        None => problems.push("Could not read the amount of memory from /proc/meminfo".to_string()),
+    }
+
+    for drive in drives {
+        match disks::find(drive)? {
+            Some(disk) => {
+                let size_gib = disk.size_bytes / 1024 / 1024 / 1024;
+                if size_gib < MIN_DISK_GIB {
+                    problems.push(format!(
+                        "Drive {} is only {} GiB, at least {} GiB is needed",
+                        drive, size_gib, MIN_DISK_GIB
+                    ));
+                }
+                if let Some(reason) = disk.unavailable_reason(resume) {
+                    problems.push(format!(
+                        "Drive {} can't be installed to because {}",
+                        drive, reason
+                    ));
+                }
+            }
+            None => problems.push(format!("Drive {} was not found in /dev/disk/by-id", drive)),
+        }
+    }
+
+    let mounts = mounts_under(&plan.stages.target);
+    if !mounts.is_empty() && !resume {
+        problems.push(format!(
+            "Something is already mounted on {}: {}",
+            plan.stages.target,
+            mounts.join(", ")
+        ));
+    }
+
+    report(runner, problems)
+}
+
+// Check that ZFS is usable once the stage installed it into the live environment.
+pub fn preflight_zfs(runner: &dyn Runner) -> Result<()> {
+    let problems = ZFS_TOOLS
+        .iter()
+        .filter(|tool| !is_installed(tool))
+        .map(|tool| format!("Required tool '{}' is not installed", tool))
+        .collect();
+
+    report(runner, problems)
+}
+
+fn report(runner: &dyn Runner, problems: Vec<String>) -> Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    if runner.dry_run() {
+        for problem in &problems {
+            println!("[dry-run] Preflight: {}", problem);
+        }
+        return Ok(());
+    }
+
+    Err(InstallError::PreconditionFailed(format!(
+        "Preflight checks failed:\n  {}",
+        problems.join("\n  ")
+    )))
+}
+
+// A tool is installed when an executable with its name is in one of the directories on PATH.
+fn is_installed(tool: &str) -> bool {
+    env::var_os("PATH")
+        .map(|path| env::split_paths(&path).any(|dir| dir.join(tool).is_file()))
+        .unwrap_or(false)
+}
+
+// Read MemTotal from /proc/meminfo, which is given in KiB.
+fn memory_mib() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib / 1024)
+}
+
+// List the mountpoints in /proc/mounts at or below the directory.
+fn mounts_under(dir: &str) -> Vec<String> {
+    let mounts = fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .filter(|mountpoint| *mountpoint == dir || mountpoint.starts_with(&format!("{}/", dir)))
+        .map(|mountpoint| mountpoint.to_string())
+        .collect()
+}
@@ -0,0 +1,130 @@
+// Validation of the ZFS properties a plan can set, so a typo is caught before anything is created instead of by zfs halfway through the stage.
+
+// Compression algorithms zfs accepts, the zstd and gzip levels are checked separately.
+const COMPRESSION: [&str; 8] = [
+    "on",
+    "off",
+    "lz4",
+    "zstd",
+    "zstd-fast",
+    "gzip",
+    "lzjb",
+    "zle",
+];
+
+// Check a filesystem property set on a dataset or on the root of the pool, returning what is wrong with it. User properties (with a ':' in their name, like com.sun:auto-snapshot) are free form apart from the auto-snapshot flag.
+pub fn check_dataset_property(name: &str, value: &str) -> Option<String> {
+    let valid = match name {
+        "compression" => is_compression(value),
+        "recordsize" => size_bytes(value)
+            .is_some_and(|size| size.is_power_of_two() && (512..=16 * 1024 * 1024).contains(&size)),
+        "quota" | "refquota" | "reservation" | "refreservation" => {
+            value == "none" || size_bytes(value).is_some()
+        }
+        "atime" | "relatime" | "exec" | "setuid" | "devices" | "readonly" | "overlay" => {
+            is_on_off(value)
+        }
+        "xattr" => ["on", "off", "sa", "dir"].contains(&value),
+        "acltype" => ["off", "noacl", "nfsv4", "posix", "posixacl"].contains(&value),
+        "dnodesize" => ["legacy", "auto", "1k", "2k", "4k", "8k", "16k"].contains(&value),
+        "sync" => ["standard", "always", "disabled"].contains(&value),
+        "logbias" => ["latency", "throughput"].contains(&value),
+        "primarycache" | "secondarycache" => ["all", "none", "metadata"].contains(&value),
+        "snapdir" => ["hidden", "visible"].contains(&value),
+        "checksum" => [
+            "on",
+            "off",
+            "fletcher2",
+            "fletcher4",
+            "sha256",
+            "sha512",
+            "skein",
+            "edonr",
+            "blake3",
+        ]
+        .contains(&value),
+        "copies" => ["1", "2", "3"].contains(&value),
+        "normalization" => ["none", "formC", "formD", "formKC", "formKD"].contains(&value),
+        "utf8only" => is_on_off(value),
+        "casesensitivity" => ["sensitive", "insensitive", "mixed"].contains(&value),
+        "com.sun:auto-snapshot" => ["true", "false"].contains(&value),
+        "mountpoint" | "canmount" => {
+            return Some(format!(
+                "{} is set with its own field, not in properties",
+                name
+            ))
+        }
+        "encryption" | "keyformat" | "keylocation" => {
+            return Some(format!("{} is set in the [encryption] section", name))
+        }
+        name if name.contains(':') => !value.is_empty(),
+        _ => return Some(format!("'{}' is not a known property", name)),
+    };
+
+    if valid {
+        None
+    } else {
+        Some(format!("'{}' is not a valid value for {}", value, name))
+    }
+}
+
+// Check a property of the pool itself, passed to zpool create with -o. Feature flags can be enabled or disabled by name.
+pub fn check_pool_property(name: &str, value: &str) -> Option<String> {
+    let valid = match name {
+        "ashift" => value
+            .parse::<u32>()
+            .is_ok_and(|ashift| (9..=16).contains(&ashift)),
+        "autotrim" | "autoexpand" | "autoreplace" | "listsnapshots" | "multihost" => {
+            is_on_off(value)
+        }
+        "failmode" => ["wait", "continue", "panic"].contains(&value),
+        "comment" => !value.contains('"'),
+        "compatibility" => !value.is_empty(),
+        "altroot" | "cachefile" | "bootfs" => {
+            return Some(format!("{} is managed by the installer", name))
+        }
+        name if name.starts_with("feature@") => ["enabled", "disabled"].contains(&value),
+        _ => return Some(format!("'{}' is not a known pool property", name)),
+    };
+
+    if valid {
+        None
+    } else {
+        Some(format!("'{}' is not a valid value for {}", value, name))
+    }
+}
+
+fn is_on_off(value: &str) -> bool {
+    value == "on" || value == "off"
+}
+
+// Accept the plain algorithms and the levelled forms like zstd-3, zstd-fast-10 and gzip-9.
+fn is_compression(value: &str) -> bool {
+    if COMPRESSION.contains(&value) {
+        return true;
+    }
+
+    let level = |prefix: &str, range: std::ops::RangeInclusive<u32>| {
+        value
+            .strip_prefix(prefix)
+            .and_then(|level| level.parse::<u32>().ok())
+            .is_some_and(|level| range.contains(&level))
+    };
+
+    level("zstd-fast-", 1..=1000) || level("zstd-", 1..=19) || level("gzip-", 1..=9)
+}
+
+// Parse sizes the way zfs writes them, a number with an optional K, M, G, T or P suffix (an optional trailing B is allowed too).
+pub fn size_bytes(value: &str) -> Option<u64> {
+    let value = value.to_ascii_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (number, multiplier) = match value.chars().last()? {
+        'K' => (&value[..value.len() - 1], 1u64 << 10),
+        'M' => (&value[..value.len() - 1], 1 << 20),
+        'G' => (&value[..value.len() - 1], 1 << 30),
+        'T' => (&value[..value.len() - 1], 1 << 40),
+        'P' => (&value[..value.len() - 1], 1 << 50),
+        _ => (value, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
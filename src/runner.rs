@@ -0,0 +1,548 @@
+use crate::error::{InstallError, Result};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// A command to run: a program with its arguments, which reach the program exactly as they are without a shell in between, so a password or a name from the plan can't be taken for shell syntax. Input like the passwords for chpasswd or the lines to add to a file is passed on stdin, which is never shown or logged.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Cmd {
+    pub program: String,
+    pub args: Vec<String>,
+    pub stdin: Option<String>,
+    // The input holds a secret like a password, it is left out of the journal's fingerprint too
+    pub secret: bool,
+    pub dir: Option<String>,
+}
+
+impl Cmd {
+    pub fn new(program: &str) -> Cmd {
+        Cmd {
+            program: program.to_string(),
+            ..Cmd::default()
+        }
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Cmd {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Cmd
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    // Pass the input to the command on its stdin.
+    pub fn stdin<S: Into<String>>(mut self, input: S) -> Cmd {
+        self.stdin = Some(input.into());
+        self
+    }
+
+    // Pass the secret input to the command on its stdin.
+    pub fn secret_stdin<S: Into<String>>(mut self, input: S) -> Cmd {
+        self.secret = true;
+        self.stdin(input)
+    }
+
+    // Run the command in the directory instead of the current one.
+    pub fn dir<S: Into<String>>(mut self, dir: S) -> Cmd {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    // Run the script through sh -c. Only a fixed script can be passed, so nothing from the plan or the user can end up in it. The exceptions are the SHELL_ prefixed scripts below, anything else is a program with arguments.
+    pub fn shell(script: &'static str) -> Cmd {
+        Cmd::new("sh").arg("-c").arg(script)
+    }
+
+    fn is_shell(&self) -> bool {
+        self.program == "sh" && self.args.first().is_some_and(|arg| arg == "-c")
+    }
+
+    // Commands that can ask for a password on the terminal, which a spinner would draw over. makepkg and yay call sudo themselves.
+    fn may_prompt(&self) -> bool {
+        PROMPTING_PROGRAMS.contains(&self.program.as_str())
+    }
+}
+
+// The audited shell exceptions. The ZFS init script for the live ISO is only published as a script to pipe into bash.
+pub const SHELL_GET_ZFS: &str =
+    "bash -c \"$(curl -s https://raw.githubusercontent.com/eoli3n/archiso-zfs/master/init)\"";
+
+// Shows the command the way it would be typed into a shell, quoting arguments where needed. The input on stdin follows on the same line with its line breaks escaped, so a dry run shows the lines that would be written. Secret input like passwords is only counted.
+impl fmt::Display for Cmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", quote(&self.program))?;
+        for arg in &self.args {
+            write!(f, " {}", quote(arg))?;
+        }
+        match &self.stdin {
+            Some(input) if self.secret => {
+                let lines = input.lines().count();
+                let unit = if lines == 1 { "line" } else { "lines" };
+                write!(f, " [stdin: hidden, {} {}]", lines, unit)?;
+            }
+            Some(input) => write!(f, " [stdin: {}]", escape(input))?,
+            None => {}
+        }
+        if let Some(dir) = &self.dir {
+            write!(f, " [in {}]", dir)?;
+        }
+        Ok(())
+    }
+}
+
+// Escape the backslashes and line breaks of the input so it stays on one line.
+fn escape(input: &str) -> String {
+    input
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t")
+}
+
+// Quote the argument for a shell when it holds anything besides the characters that are safe on their own.
+fn quote(arg: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:=@%+,".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+// The result of running a single command, kept whole so callers can decide what to do with a failure instead of the runner exiting for them.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub command: Cmd,
+    pub status: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+    pub duration: Duration,
+}
+
+impl CommandOutput {
+    // A command succeeded when it exited with status 0, being killed by a signal leaves no status and counts as a failure.
+    pub fn success(&self) -> bool {
+        self.status == Some(0)
+    }
+}
+
+// Everything the stages run goes through a Runner, so the same stage code can run commands for real or only record them.
+pub trait Runner {
+    // Run the command and return its output, an error is only returned when the command could not be started at all.
+    fn run(&mut self, command: &Cmd) -> Result<CommandOutput>;
+
+    // Run the command and turn a non-zero exit status into an error carrying the command and its stderr. A shell exits with 127 when a program in its script isn't installed.
+    fn execute(&mut self, command: &Cmd) -> Result<CommandOutput> {
+        let output = self.run(command)?;
+
+        if command.is_shell() && output.status == Some(127) {
+            if let Some(program) = missing_program(&output.stderr) {
+                return Err(InstallError::MissingTool(program));
+            }
+        }
+
+        if !output.success() {
+            return Err(InstallError::CommandFailed {
+                command: command.to_string(),
+                status: output.status,
+                stderr: output.stderr,
+            });
+        }
+
+        Ok(output)
+    }
+
+    // Whether commands are only recorded instead of run, checks that inspect the live system only warn in a dry run.
+    fn dry_run(&self) -> bool {
+        false
+    }
+
+    // Execute a command that is one step of the install. The id stays the same between runs of the same plan, runners that keep a journal use it to skip steps that already completed.
+    fn execute_step(&mut self, _id: &str, command: &Cmd) -> Result<()> {
+        self.execute(command)?;
+        Ok(())
+    }
+
+    // Whether the step already completed with the same command in an earlier run, only runners that keep a journal know.
+    fn step_done(&self, _id: &str, _command: &Cmd) -> bool {
+        false
+    }
+
+    // Perform a step of the install that is done by the installer itself instead of by a command, like writing a partition table. The description stands in for the command: it is what a dry run shows and what a journal fingerprints.
+    fn perform_step(
+        &mut self,
+        _id: &str,
+        _description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        action()
+    }
+
+    // Perform a step that runs again on every run, also when resuming, because what it does depends on the run, like copying the log or the plan into the new system. It is never skipped by a journal, a dry run still only shows the description.
+    fn perform_always(
+        &mut self,
+        _id: &str,
+        _description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        action()
+    }
+}
+
+// Execute the commands of one section of a stage in order, each as a step with an id like "zfs_partition_drive/2".
+pub fn execute_steps(runner: &mut dyn Runner, section: &str, commands: &[Cmd]) -> Result<()> {
+    for (index, command) in commands.iter().enumerate() {
+        // A dry run only lists the commands, a real run shows how far along the section is above the output of every command
+        if !runner.dry_run() {
+            println!(
+                "[{}/{}] {}: {}",
+                index + 1,
+                commands.len(),
+                section,
+                command.to_string().replace('\n', "\\n")
+            );
+        }
+        runner.execute_step(&format!("{}/{}", section, index), command)?;
+    }
+    Ok(())
+}
+
+// Runs commands for real, streaming their stdout and stderr line by line as they run while keeping all of it for the result. On a terminal a spinner with the elapsed time shows the command is still running when it has been quiet for a while, like pacstrap downloading packages.
+pub struct ProcessRunner;
+
+// The frames of the spinner, how often it is redrawn and how long a command has to be quiet before it shows.
+const SPINNER: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+const SPINNER_DELAY: Duration = Duration::from_secs(2);
+
+// The programs that never get a spinner, see Cmd::may_prompt.
+const PROMPTING_PROGRAMS: [&str; 3] = ["sudo", "makepkg", "yay"];
+
+// A line of output, from stderr when the flag is set.
+type OutputLine = (bool, String);
+
+impl Runner for ProcessRunner {
+    fn run(&mut self, command: &Cmd) -> Result<CommandOutput> {
+        let started = Instant::now();
+        let mut process = Command::new(&command.program);
+        process
+            .args(&command.args)
+            .stdin(if command.stdin.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(dir) = &command.dir {
+            process.current_dir(dir);
+        }
+        let mut child = match process.spawn() {
+            Ok(child) => child,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(InstallError::MissingTool(command.program.clone()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        // The input is written on its own thread too, a command that answers before reading all of it would otherwise block both sides. Closing stdin once it's written tells the command the input is complete.
+        let writer = match (child.stdin.take(), command.stdin.clone()) {
+            (Some(mut stdin), Some(input)) => Some(thread::spawn(move || {
+                let _ = stdin.write_all(input.as_bytes());
+            })),
+            _ => None,
+        };
+
+        // Every stream is read on its own thread so neither can fill its pipe and block the command while the other is read
+        let (sender, receiver) = mpsc::channel::<OutputLine>();
+        let mut readers = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            readers.push(read_lines(stdout, false, sender.clone()));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            readers.push(read_lines(stderr, true, sender.clone()));
+        }
+        drop(sender);
+
+        let spinner = io::stdout().is_terminal() && !command.may_prompt();
+        let mut spinning = false;
+        let mut quiet_since = Instant::now();
+        let mut frame = 0;
+        let mut stdout = String::new();
+        let mut stderr = String::new();
+        loop {
+            match receiver.recv_timeout(SPINNER_INTERVAL) {
+                Ok((is_stderr, line)) => {
+                    if spinning {
+                        print!("\r\x1b[K");
+                        spinning = false;
+                    }
+                    quiet_since = Instant::now();
+                    if is_stderr {
+                        eprintln!("{}", line);
+                        stderr.push_str(&line);
+                        stderr.push('\n');
+                    } else {
+                        println!("{}", line);
+                        stdout.push_str(&line);
+                        stdout.push('\n');
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            }
+
+            // Only once the command has been quiet for a while, output is always a whole line so the spinner never draws over part of one
+            if spinner && quiet_since.elapsed() >= SPINNER_DELAY {
+                print!(
+                    "\r\x1b[K{} {}s",
+                    SPINNER[frame % SPINNER.len()],
+                    started.elapsed().as_secs()
+                );
+                io::stdout().flush()?;
+                spinning = true;
+                frame += 1;
+            }
+        }
+        for reader in readers {
+            let _ = reader.join();
+        }
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+        let status = child.wait()?;
+
+        let output = CommandOutput {
+            command: command.clone(),
+            status: status.code(),
+            stdout,
+            stderr,
+            duration: started.elapsed(),
+        };
+
+        if spinning {
+            print!("\r\x1b[K");
+        }
+        if output.success() {
+            println!("  done in {:.1}s", output.duration.as_secs_f64());
+        } else {
+            println!(
+                "  failed with status {} after {:.1}s",
+                output
+                    .status
+                    .map_or("none".to_string(), |status| status.to_string()),
+                output.duration.as_secs_f64()
+            );
+        }
+
+        Ok(output)
+    }
+}
+
+// Send every line of the stream to the receiver until it closes, output that isn't valid UTF-8 is converted lossily like before.
+fn read_lines<R: Read + Send + 'static>(
+    stream: R,
+    is_stderr: bool,
+    sender: mpsc::Sender<OutputLine>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut line = Vec::new();
+        while let Ok(read) = reader.read_until(b'\n', &mut line) {
+            if read == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line)
+                .trim_end_matches(['\n', '\r'])
+                .to_string();
+            if sender.send((is_stderr, text)).is_err() {
+                break;
+            }
+            line.clear();
+        }
+    })
+}
+
+// Records every command instead of running it, used for dry runs. Commands succeed with empty output unless a canned response or a failure was registered for a command starting with the given prefix.
+#[derive(Debug, Default)]
+pub struct RecordingRunner {
+    pub commands: Vec<String>,
+    responses: Vec<(String, String)>,
+    failures: Vec<String>,
+    echo: bool,
+}
+
+impl RecordingRunner {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        RecordingRunner::default()
+    }
+
+    // A recorder that also prints every command as it would be run, in the order the stages run them.
+    pub fn echoing() -> Self {
+        RecordingRunner {
+            echo: true,
+            ..RecordingRunner::default()
+        }
+    }
+
+    // Answer commands starting with the prefix with the given stdout.
+    pub fn respond(&mut self, prefix: &str, stdout: &str) {
+        self.responses
+            .push((prefix.to_string(), stdout.to_string()));
+    }
+
+    // Make commands starting with the prefix exit with status 1.
+    #[cfg(test)]
+    pub fn fail(&mut self, prefix: &str) {
+        self.failures.push(prefix.to_string());
+    }
+}
+
+impl Runner for RecordingRunner {
+    fn dry_run(&self) -> bool {
+        true
+    }
+
+    fn run(&mut self, command: &Cmd) -> Result<CommandOutput> {
+        let line = command.to_string();
+        self.commands.push(line.clone());
+
+        // Keep every command on a single line so two dry runs can be diffed line by line.
+        if self.echo {
+            println!("[dry-run] {}", line.replace('\n', "\\n"));
+        }
+
+        let stdout = self
+            .responses
+            .iter()
+            .find(|(prefix, _)| line.starts_with(prefix.as_str()))
+            .map(|(_, stdout)| stdout.clone())
+            .unwrap_or_default();
+        let failed = self
+            .failures
+            .iter()
+            .any(|prefix| line.starts_with(prefix.as_str()));
+
+        Ok(CommandOutput {
+            command: command.clone(),
+            status: Some(if failed { 1 } else { 0 }),
+            stdout,
+            stderr: String::new(),
+            duration: Duration::ZERO,
+        })
+    }
+
+    // Only the description is recorded, nothing is performed.
+    fn perform_step(
+        &mut self,
+        _id: &str,
+        description: &str,
+        _action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        self.commands.push(description.to_string());
+        if self.echo {
+            println!("[dry-run] {}", description);
+        }
+        Ok(())
+    }
+
+    fn perform_always(
+        &mut self,
+        id: &str,
+        description: &str,
+        action: &mut dyn FnMut() -> Result<()>,
+    ) -> Result<()> {
+        self.perform_step(id, description, action)
+    }
+}
+
+// Find the program the shell couldn't find in its error, which looks like "sh: 1: blkdiscard: not found" or "sh: line 1: blkdiscard: command not found".
+fn missing_program(stderr: &str) -> Option<String> {
+    let line = stderr.lines().find(|line| line.ends_with("not found"))?;
+    let parts: Vec<&str> = line.split(": ").collect();
+    parts
+        .len()
+        .checked_sub(2)
+        .map(|index| parts[index].trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_show_their_input_unless_it_is_secret() {
+        let tee = Cmd::new("tee")
+            .args(["-a", "/etc/fstab"])
+            .stdin("zroot/data /data zfs defaults 0 0\n");
+        assert_eq!(
+            tee.to_string(),
+            "tee -a /etc/fstab [stdin: zroot/data /data zfs defaults 0 0\\n]"
+        );
+
+        let chpasswd = Cmd::new("chpasswd").secret_stdin("alice:secret\nbob:secret\n");
+        assert_eq!(chpasswd.to_string(), "chpasswd [stdin: hidden, 2 lines]");
+    }
+
+    #[test]
+    fn process_runner_captures_stdout_stderr_and_the_status() {
+        let output = ProcessRunner
+            .run(&Cmd::new("sh").args(["-c", "echo out; echo err >&2; echo more; exit 3"]))
+            .unwrap();
+
+        assert_eq!(output.stdout, "out\nmore\n");
+        assert_eq!(output.stderr, "err\n");
+        assert_eq!(output.status, Some(3));
+        assert!(!output.success());
+    }
+
+    #[test]
+    fn process_runner_passes_the_input_on_stdin() {
+        // More than a pipe buffer holds, so the input is only complete when it's written while the output is read
+        let input: String = (0..20_000).map(|line| format!("line {}\n", line)).collect();
+        let output = ProcessRunner
+            .run(&Cmd::new("cat").stdin(input.clone()))
+            .unwrap();
+
+        assert_eq!(output.stdout, input);
+        assert_eq!(output.stderr, "");
+        assert_eq!(output.status, Some(0));
+    }
+
+    #[test]
+    fn process_runner_runs_in_the_directory() {
+        let output = ProcessRunner.run(&Cmd::new("pwd").dir("/")).unwrap();
+        assert_eq!(output.stdout, "/\n");
+    }
+
+    #[test]
+    fn process_runner_reports_a_missing_program() {
+        let err = ProcessRunner
+            .run(&Cmd::new("install-test-no-such-program"))
+            .unwrap_err();
+        assert!(
+            matches!(err, InstallError::MissingTool(program) if program == "install-test-no-such-program")
+        );
+    }
+
+    #[test]
+    fn execute_turns_a_failed_command_into_an_error() {
+        let err = ProcessRunner
+            .execute(&Cmd::new("sh").args(["-c", "echo broken >&2; exit 3"]))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            InstallError::CommandFailed { status: Some(3), ref stderr, .. } if stderr == "broken\n"
+        ));
+    }
+}
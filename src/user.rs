@@ -1,186 +1,243 @@
+use crate::error::{InstallError, Result, StepContext};
+use crate::plan::Plan;
+use crate::runner::{execute_steps, Cmd, Runner};
+use std::env;
 use std::io::{self, Write};
-use std::process::Command;
-use std::thread;
-use std::time::Duration;
 
-// This function executes a series of shell commands to install packages and perform other setup tasks for the user.
-pub fn user() {
+// This function executes a series of commands to install packages and perform other setup tasks for the user.
+pub fn user(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    // The user stage sets up the home directory of whoever runs it, so it must not be run as root
+    if runner.execute(&Cmd::new("whoami"))?.stdout.trim() == "root" {
+        return Err(InstallError::PreconditionFailed(
+            "The user stage must be run as your own user, not as root".to_string(),
+        ));
+    }
+
     // Create the user's home directory
-    user_create_home();
+    user_create_home(runner).during("Creating the home directory")?;
 
     // Install Yay packages
-    user_yay_packages();
+    user_yay_packages(plan, runner).during("Installing packages")?;
 
     // Install dotfiles
-    user_install_dotfiles();
+    user_install_dotfiles(plan, runner).during("Installing dotfiles")?;
 
     // Install additional packages
-    user_extras();
-
-    // Ask the user if they want to use Stetsed's dotfiles and install them if they say yes
-    let mut input = String::new();
-    print!("Do you want to use Stetsed's Home Configuration(Say no if your not Stetsed)? (y/n): ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input).unwrap();
-
-    if input.trim().eq_ignore_ascii_case("y") {
-        user_extras_stetsed();
+    user_extras(plan, runner).during("Setting up extras")?;
+
+    // Ask the user if they want to use Stetsed's dotfiles when the plan doesn't say, and install them if they say yes
+    let stetsed_extras = match plan.dotfiles.stetsed_extras {
+        Some(stetsed_extras) => stetsed_extras,
+        None => {
+            let mut input = String::new();
+            print!("Do you want to use Stetsed's Home Configuration(Say no if your not Stetsed)? (y/n): ");
+            io::stdout().flush()?;
+            io::stdin().read_line(&mut input)?;
+            input.trim().eq_ignore_ascii_case("y")
+        }
+    };
+
+    if stetsed_extras {
+        user_extras_stetsed(runner).during("Setting up Stetsed's extras")?;
     }
 
-    // Print a thank-you message and exit the program
-    print!("Thank you for using Stetsed's Installer! Hope it helped you! :");
-    std::process::exit(0);
+    // Print a thank-you message
+    println!("Thank you for using Stetsed's Installer! Hope it helped you! :)");
+
+    Ok(())
 }
 
-// This function creates the home directory for the current user by executing a series of shell commands.
-pub fn user_create_home() -> std::io::Result<String> {
+// This function creates the home directory for the current user by executing a series of commands.
+pub fn user_create_home(runner: &mut dyn Runner) -> Result<String> {
     // Get the current user's name
-    let output = Command::new("whoami")
-        .output()
-        .expect("failed to execute process");
-    let whoami_output = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let whoami_output = runner
+        .execute(&Cmd::new("whoami"))?
+        .stdout
+        .trim()
+        .to_string();
+    let home = format!("/home/{}", whoami_output);
 
     // Create the user's home directory and set the owner and permissions
     let commands = vec![
-        format!("sudo mkdir /home/{}", whoami_output),
-        format!(
-            "sudo chown {}:{} -R /home/{}",
-            whoami_output, whoami_output, whoami_output
-        ),
-        format!("sudo chmod 700 /home/{}", whoami_output),
+        Cmd::new("sudo").args(["mkdir", home.as_str()]),
+        Cmd::new("sudo")
+            .args(["chown", "-R"])
+            .arg(format!("{}:{}", whoami_output, whoami_output))
+            .arg(&home),
+        Cmd::new("sudo").args(["chmod", "700", home.as_str()]),
     ];
 
-    // Execute the commands in the vector
-    for command in commands {
-        execute_command(&command);
-    }
+    // Execute the commands in the vector as steps
+    execute_steps(runner, "user_create_home", &commands)?;
 
     // Return a `String` indicating the completion of the operation
-    Ok(format!("Home Created"))
+    Ok("Home Created".to_string())
 }
 
-pub fn user_yay_packages() -> std::io::Result<String> {
+pub fn user_yay_packages(plan: &Plan, runner: &mut dyn Runner) -> Result<String> {
     // Get the username of the current user
-    let output = Command::new("whoami")
-        .output()
-        .expect("failed to execute process");
-
-    let whoami_output = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let whoami_output = runner
+        .execute(&Cmd::new("whoami"))?
+        .stdout
+        .trim()
+        .to_string();
+    let home = format!("/home/{}", whoami_output);
+    let yay = format!("{}/yay-bin", home);
 
     let commands = vec![
         // Clone the yay package manager from AUR
-        format!("cd /home/{} && git clone https://aur.archlinux.org/yay-bin.git", whoami_output),
-        // Build and install the yay package manager
-        format!("cd /home/{} && cd yay-bin && makepkg -s && sudo pacman -U --noconfirm yay-bin* && cd .. && rm -rf yay-bin", whoami_output),
+        Cmd::new("git")
+            .args(["clone", "https://aur.archlinux.org/yay-bin.git"])
+            .dir(&home),
+        // Build and install the yay package manager, makepkg installs it with pacman through sudo
+        Cmd::new("makepkg").args(["-si", "--noconfirm"]).dir(&yay),
+        Cmd::new("rm").args(["-rf", yay.as_str()]),
         // Install packages using yay
-        "yay -Syu --noconfirm --answerdiff=None imagemagick rust-analyzer kitty ripgrep unzip bat pavucontrol pipewire-pulse dunst bluedevil bluez-utils brightnessctl grimblast-git neovim network-manager-applet rofi-lbonn-wayland-git starship thunar thunar-archive-plugin thunar-volman  webcord-bin wl-clipboard librewolf-bin neofetch swaybg waybar-hyprland-git btop tldr swaylock-effects obsidian fish hyprland npm xdg-desktop-portal-hyprland-git exa noto-fonts-emoji qt5-wayland qt6-wayland blueman swappy playerctl wlogout sddm-git nano ttf-jetbrains-mono-nerd lazygit swayidle".to_string(),
+        Cmd::new("yay")
+            .args(["-Syu", "--noconfirm", "--answerdiff=None"])
+            .args(&plan.packages.aur),
     ];
 
-    for command in commands {
-        execute_command(&command);
-    }
-    Ok(format!("Yay and Packages Installed"))
+    execute_steps(runner, "user_yay_packages", &commands)?;
+    Ok("Yay and Packages Installed".to_string())
 }
 
-// Function to install dotfiles from a user's repository or Stetsed's Dotfiles
-pub fn user_install_dotfiles() -> std::io::Result<String> {
-    // Initialize variables to hold the URLs of the dotfiles repositories
-    let mut dotfiles_url = String::new();
-    let mut ssh_url = String::new();
-
-    // Ask the user if they want to use Stetsed's Dotfiles or their own
-    let mut input = String::new();
-    print!("Do you want to use Stetsed's Dotfiles? (y/n): ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut input).unwrap();
-
-    // If the user wants to use Stetsed's Dotfiles, set the URLs accordingly
-    if input.trim().eq_ignore_ascii_case("y") {
-        dotfiles_url = "https://github.com/Stetsed/.dotfiles.git".to_owned();
-        ssh_url = "git@github.com:Stetsed/.dotfiles.git".to_owned();
-    } else {
-        // Ask the user for their github dotfiles repository and set the URLs accordingly
-        print!("Enter your github dotfiles repository (username/repository_name): ");
-        let mut dotfiles_repo = String::new();
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut dotfiles_repo).unwrap();
-        let dotfiles_repo_trim = dotfiles_repo.trim().to_owned();
-        dotfiles_url = format!("https://github.com/{}.git", dotfiles_repo_trim);
-        ssh_url = format!("git@github.com:{}.git", dotfiles_repo_trim);
-    }
+// Function to install dotfiles from the plan's repository, a user's repository or Stetsed's Dotfiles
+pub fn user_install_dotfiles(plan: &Plan, runner: &mut dyn Runner) -> Result<String> {
+    // Use the repository from the plan, otherwise ask the user if they want to use Stetsed's Dotfiles or their own
+    let dotfiles_repo = match &plan.dotfiles.repository {
+        Some(repository) => repository.clone(),
+        None => {
+            let mut input = String::new();
+            print!("Do you want to use Stetsed's Dotfiles? (y/n): ");
+            io::stdout().flush()?;
+            io::stdin().read_line(&mut input)?;
+
+            if input.trim().eq_ignore_ascii_case("y") {
+                "Stetsed/.dotfiles".to_owned()
+            } else {
+                // Ask the user for their github dotfiles repository
+                print!("Enter your github dotfiles repository (username/repository_name): ");
+                let mut dotfiles_repo = String::new();
+                io::stdout().flush()?;
+                io::stdin().read_line(&mut dotfiles_repo)?;
+                dotfiles_repo.trim().to_owned()
+            }
+        }
+    };
+
+    // Set the URLs of the dotfiles repository
+    let dotfiles_url = format!("https://github.com/{}.git", dotfiles_repo);
+    let ssh_url = format!("git@github.com:{}.git", dotfiles_repo);
+
+    // Every git command works on the bare repository in ~/.dotfiles with the home directory as its work tree
+    let home = user_home()?;
+    let dotfiles = format!("{}/.dotfiles", home);
+    let git = || {
+        Cmd::new("/usr/bin/git")
+            .arg(format!("--git-dir={}/", dotfiles))
+            .arg(format!("--work-tree={}", home))
+    };
 
     // Create a vector of commands to execute
     let commands = vec![
-        format!("git clone --bare {} $HOME/.dotfiles", dotfiles_url), // Clone the dotfiles repository to ~/.dotfiles
-        "/usr/bin/git --git-dir=$HOME/.dotfiles/ --work-tree=$HOME checkout -f".to_string(), // Checkout the dotfiles to the home directory
-        "/usr/bin/git --git-dir=$HOME/.dotfiles/ --work-tree=$HOME config status.showUntrackedFiles no".to_string(), // Ignore untracked files in the dotfiles directory
-        format!("/usr/bin/git --git-dir=$HOME/.dotfiles/ --work-tree=$HOME config remote set-url origin {}", ssh_url), // Set the origin URL of the dotfiles repository
+        Cmd::new("git").args(["clone", "--bare", dotfiles_url.as_str(), dotfiles.as_str()]), // Clone the dotfiles repository to ~/.dotfiles
+        git().args(["checkout", "-f"]), // Checkout the dotfiles to the home directory
+        git().args(["config", "status.showUntrackedFiles", "no"]), // Ignore untracked files in the dotfiles directory
+        git().args(["config", "remote", "set-url", "origin", ssh_url.as_str()]), // Set the origin URL of the dotfiles repository
     ];
 
-    // Loop through the commands and execute each one
-    for command in commands {
-        execute_command(&command);
-    }
+    // Execute the commands as steps
+    execute_steps(runner, "user_install_dotfiles", &commands)?;
 
-    Ok(format!("Dotfiles Installed")) // Return a success message
+    Ok("Dotfiles Installed".to_string()) // Return a success message
 }
 
-// Function to enable and configure various system services
-pub fn user_extras() -> std::io::Result<String> {
-    // Create a vector of commands to execute
-    let commands = vec![
-        "sudo systemctl enable --now bluetooth && sudo systemctl enable sddm", // Enable and start Bluetooth and SDDM services
-        "systemctl --user enable --now pipewire", // Enable and start the user-level pipewire service
-        "systemctl enable --now pipewire-pulse",  // Enable and start the pipewire-pulse service
-        "sudo timedatectl set-ntp true && sudo timedatectl set-timezone Europe/Amsterdam", // Set the timezone and enable NTP synchronization
-    ];
+// Function to enable and configure the desktop and user services and the timezone from the plan
+pub fn user_extras(plan: &Plan, runner: &mut dyn Runner) -> Result<String> {
+    let mut commands = Vec::new();
+
+    // Enable the desktop services, they are started on the next boot
+    for service in &plan.services.desktop {
+        commands.push(Cmd::new("sudo").args(["systemctl", "enable", service.as_str()]));
+    }
 
-    // Loop through the commands and execute each one
-    for command in commands {
-        execute_command(&command);
+    // Enable and start the user-level services
+    for service in &plan.services.user {
+        commands.push(Cmd::new("systemctl").args(["--user", "enable", "--now", service.as_str()]));
     }
 
-    Ok(format!("Extra's Done")) // Return a success message
+    // Enable NTP synchronization and set the timezone
+    commands.push(Cmd::new("sudo").args(["timedatectl", "set-ntp", "true"]));
+    commands.push(Cmd::new("sudo").args([
+        "timedatectl",
+        "set-timezone",
+        plan.system.timezone.as_str(),
+    ]));
+
+    // Execute the commands as steps
+    execute_steps(runner, "user_extras", &commands)?;
+
+    Ok("Extra's Done".to_string()) // Return a success message
 }
 
 // Function to enable and configure various system services specific to Stetsed's setup
-pub fn user_extras_stetsed() -> std::io::Result<String> {
+pub fn user_extras_stetsed(runner: &mut dyn Runner) -> Result<String> {
+    let home = user_home()?;
+
     // Create a vector of commands to execute
     let commands = vec![
-        "echo '10.4.78.251:/mnt/Vault/Storage /mnt/data nfs defaults,_netdev,x-systemd.automount,x-systemd.mount-timeout=10,noauto 0 0' | sudo tee -a /etc/fstab", // Add an NFS mount to /etc/fstab
-        "sudo mkdir /mnt/data", // Create a mount point directory
-        "sudo mount -t nfs 10.4.78.251:/mnt/Vault/Storage /mnt/data", // Mount the NFS share to the directory
-        "ln -s /mnt/data/Stetsed/Storage ~/Storage", // Create a symlink for Stetsed's Storage directory
-        "ln -s /mnt/data/Stetsed/Documents ~/Documents", // Create a symlink for Stetsed's Documents directory
-        "echo -e '[Autologin]\nUser=stetsed\nSession=hyprland' | sudo tee -a /etc/sddm.conf", // Configure SDDM to autologin as user 'stetsed' and use the 'hyprland' session
-        "sudo groupadd autologin && sudo usermod -aG autologin stetsed", // Add the user 'stetsed' to the 'autologin' group
+        Cmd::new("sudo").args(["tee", "-a", "/etc/fstab"]).stdin("10.4.78.251:/mnt/Vault/Storage /mnt/data nfs defaults,_netdev,x-systemd.automount,x-systemd.mount-timeout=10,noauto 0 0\n"), // Add an NFS mount to /etc/fstab
+        Cmd::new("sudo").args(["mkdir", "/mnt/data"]), // Create a mount point directory
+        Cmd::new("sudo").args(["mount", "-t", "nfs", "10.4.78.251:/mnt/Vault/Storage", "/mnt/data"]), // Mount the NFS share to the directory
+        Cmd::new("ln").args(["-s", "/mnt/data/Stetsed/Storage"]).arg(format!("{}/Storage", home)), // Create a symlink for Stetsed's Storage directory
+        Cmd::new("ln").args(["-s", "/mnt/data/Stetsed/Documents"]).arg(format!("{}/Documents", home)), // Create a symlink for Stetsed's Documents directory
+        Cmd::new("sudo").args(["tee", "-a", "/etc/sddm.conf"]).stdin("[Autologin]\nUser=stetsed\nSession=hyprland\n"), // Configure SDDM to autologin as user 'stetsed' and use the 'hyprland' session
+        Cmd::new("sudo").args(["groupadd", "autologin"]), // Add the user 'stetsed' to the 'autologin' group
+        Cmd::new("sudo").args(["usermod", "-aG", "autologin", "stetsed"]),
     ];
 
-    // Loop through the commands and execute each one
-    for command in commands {
-        execute_command(&command);
-    }
-    Ok(format!("Stetsed Extra's Done")) // Return a success message
+    // Execute the commands as steps
+    execute_steps(runner, "user_extras_stetsed", &commands)?;
+    Ok("Stetsed Extra's Done".to_string()) // Return a success message
 }
 
-pub fn execute_command(command: &str) -> std::io::Result<()> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&command)
-        .output()
-        .expect("Failed to execute command");
-
-    if !output.status.success() {
-        eprintln!(
-            "Command '{}' failed with exit status: {:?}",
-            command, output.status
+// The home directory of the user running the stage, which the shell used to fill in for $HOME and ~.
+fn user_home() -> Result<String> {
+    env::var("HOME").map_err(|_| {
+        InstallError::PreconditionFailed("HOME is not set for the user stage".to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
+
+    #[test]
+    fn extras_enable_the_services_and_set_the_timezone() {
+        let plan = Plan::parse(
+            r#"
+            [services]
+            desktop = ["sddm"]
+            user = ["pipewire"]
+
+            [system]
+            timezone = "America/New_York"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+        user_extras(&plan, &mut runner).unwrap();
+
+        assert_eq!(
+            runner.commands,
+            [
+                "sudo systemctl enable sddm",
+                "systemctl --user enable --now pipewire",
+                "sudo timedatectl set-ntp true",
+                "sudo timedatectl set-timezone America/New_York",
+            ]
         );
-        std::process::exit(1);
     }
-
-    println!("{}", String::from_utf8_lossy(&output.stdout));
-
-    thread::sleep(Duration::from_secs(3));
-
-    Ok(())
 }
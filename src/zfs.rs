@@ -1,180 +1,1111 @@
+use crate::chroot;
+use crate::disks::{self, Disk, Transport};
+use crate::error::{InstallError, Result, StepContext};
+use crate::finish;
+use crate::gpt::{self, Gpt};
+use crate::journal::{JOURNAL_PATH, PLAN_FILE};
+use crate::log;
+use crate::plan::{KeySource, PartitionKind, Plan, Topology, WipeStrategy};
+use crate::preflight::{preflight, preflight_zfs};
+use crate::properties::size_bytes;
+use crate::runner::{execute_steps, Cmd, Runner, SHELL_GET_ZFS};
 use std::fs;
-use std::io::{self, Write};
-use std::process::Command;
-use std::thread;
-use std::time::Duration;
-
-pub fn zfs() {
-    // Call the necessary sub-functions in the correct order.
-    zfs_get_zfs().expect("Failed to install ZFS");
-    let selected_drive = zfs_select_drive().unwrap_or_else(|err| {
-        eprintln!("Failed to select drive: {}", err);
-        String::new()
-    });
-    zfs_partition_drive(&selected_drive).expect("Failed to partition drive");
-    zfs_setup_filesystem(&selected_drive).expect("Failed to setup filesystem");
-    zfs_setup_basesystem().expect("Failed to setup basesystem");
-
-    // Exit the program with a successful status code.
-    std::process::exit(0);
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+pub fn zfs(plan: &Plan, runner: &mut dyn Runner, yes: bool, resume: bool) -> Result<()> {
+    // Call the necessary sub-functions in the correct order, only asking for the drives and topology when the plan doesn't give them.
+    // An install to a disk image has no drives to select, the image is attached as a loop device once the checks passed
+    let selected_drives = if plan.disk.image.is_some() || !plan.disk.drives.is_empty() {
+        plan.disk.drives.clone()
+    } else {
+        zfs_select_drives(resume).during("Selecting the drives")?
+    };
+    let topology = match plan.pool.topology {
+        Some(topology) => topology,
+        None if selected_drives.len() <= 1 => Topology::Stripe,
+        None => zfs_select_topology().during("Selecting the pool topology")?,
+    };
+    if plan.disk.image.is_none() && selected_drives.len() < topology.min_drives() {
+        return Err(InstallError::InvalidInput(format!(
+            "A {} pool needs at least {} drives, {} selected",
+            topology.name(),
+            topology.min_drives(),
+            selected_drives.len()
+        )));
+    }
+
+    // Check everything the stage needs before it changes anything, let alone wipes a drive
+    preflight(runner, plan, &selected_drives, resume).during("Preflight checks")?;
+
+    // Ask for the passphrase of an encrypted pool up front so the rest of the stage runs unattended
+    let key_location = zfs_prepare_key(plan, runner).during("Preparing the encryption key")?;
+    // The same goes for the answers of the chroot stage when the stage continues into it
+    let answers = if plan.stages.chroot {
+        Some(chroot::chroot_answers(plan).during("Asking for the chroot stage")?)
+    } else {
+        None
+    };
+    zfs_get_zfs(runner).during("Installing ZFS")?;
+    preflight_zfs(runner).during("Preflight checks")?;
+
+    let selected_drives = match &plan.disk.image {
+        Some(image) => {
+            vec![zfs_attach_image(plan, runner, image).during("Attaching the disk image")?]
+        }
+        None => {
+            let mut wipes = Vec::new();
+            for drive in &selected_drives {
+                let commands = zfs_wipe_commands(plan, runner, drive)
+                    .during(&format!("Wiping drive {}", drive))?;
+                wipes.push((drive, commands));
+            }
+
+            // A resumed install that already wiped every drive doesn't ask again, the drives are already the ones from the earlier run
+            let wiped = resume
+                && wipes.iter().all(|(drive, commands)| {
+                    !commands.is_empty()
+                        && commands.iter().enumerate().all(|(index, command)| {
+                            runner
+                                .step_done(&format!("zfs_wipe_drive/{}/{}", drive, index), command)
+                        })
+                });
+            if !wiped {
+                zfs_confirm_wipe(runner, &selected_drives, yes).during("Confirming the drives")?;
+            }
+            for (drive, commands) in &wipes {
+                execute_steps(runner, &format!("zfs_wipe_drive/{}", drive), commands)
+                    .during(&format!("Wiping drive {}", drive))?;
+            }
+            selected_drives
+        }
+    };
+
+    // Every drive gets the same partition layout
+    for drive in &selected_drives {
+        zfs_partition_drive(plan, runner, drive)
+            .during(&format!("Partitioning drive {}", drive))?;
+    }
+    zfs_setup_filesystem(
+        plan,
+        runner,
+        &selected_drives,
+        topology,
+        key_location.as_deref(),
+    )
+    .during("Setting up the ZFS filesystem")?;
+    zfs_setup_basesystem(plan, runner, &selected_drives).during("Setting up the base system")?;
+    zfs_copy_installer(plan, runner).during("Copying the installer")?;
+
+    if let Some(answers) = &answers {
+        zfs_continue_into_chroot(plan, answers, runner).during("Running the chroot stage")?;
+    }
+    log::copy_to_target(plan, runner, "zfs").during("Copying the install log")?;
+    if plan.stages.finish {
+        finish::finish(plan, runner)?;
+    }
+
+    if plan.disk.image.is_some() {
+        zfs_detach_image(plan, runner, &selected_drives[0]).during("Detaching the disk image")?;
+    }
+
+    Ok(())
 }
 
-// Function to download and install ZFS.
-pub fn zfs_get_zfs() -> std::io::Result<String> {
-    // Execute the curl command to download the ZFS installation script.
-    let output = Command::new("curl")
-        .args(&[
-            "-s",
-            "https://raw.githubusercontent.com/eoli3n/archiso-zfs/master/init",
-        ])
-        .output()
-        .expect("failed to execute curl command");
-
-    // Convert the output to a string and execute the downloaded script using the bash command.
-    let output_str = String::from_utf8_lossy(&output.stdout);
-    Command::new("bash")
-        .arg("-c")
-        .arg(output_str.trim())
-        .status()
-        .expect("failed to execute bash command");
+// This function copies the running installer, found through /proc/self/exe wherever it was started from, to the installer path in the new system so the later stages can be run from there. The plan is copied next to the journal in /var/lib/install without its passwords, together with the journal so far, so the later stages run with the same plan and can pick up where the ZFS stage left off. Both are copied again on every run, a resumed run has a newer journal and maybe a fixed plan. Only root can read them.
+pub fn zfs_copy_installer(plan: &Plan, runner: &mut dyn Runner) -> Result<()> {
+    let installer = plan.in_target(&plan.stages.installer);
+    runner.perform_step(
+        "zfs_copy_installer/installer",
+        &format!("Copy the installer from /proc/self/exe to {}", installer),
+        &mut || {
+            if let Some(parent) = Path::new(&installer).parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy("/proc/self/exe", &installer)?;
+            fs::set_permissions(&installer, fs::Permissions::from_mode(0o755))?;
+            Ok(())
+        },
+    )?;
 
-    // Return a success message.
-    Ok(format!("Installed ZFS"))
+    let journal = PathBuf::from(plan.in_target(JOURNAL_PATH));
+    let state = journal.parent().unwrap_or(Path::new("/"));
+    // The passwords stay out of the copy, the chroot stage gets them through its answers file and asks for them when it's run by hand
+    let mut copy = plan.clone();
+    for user in copy.users.iter_mut() {
+        user.password = None;
+    }
+    copy.root.password = None;
+
+    runner.perform_always(
+        "zfs_copy_installer/plan",
+        &format!("Copy the plan and the journal to {}", state.display()),
+        &mut || {
+            fs::create_dir_all(state)?;
+            let contents =
+                toml::to_string(&copy).map_err(|err| InstallError::InvalidPlan(err.to_string()))?;
+            fs::OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .mode(0o600)
+                .open(state.join(PLAN_FILE))?
+                .write_all(contents.as_bytes())?;
+
+            // A run without a journal yet, like one where every earlier step was skipped, has nothing to copy
+            if Path::new(JOURNAL_PATH).exists() {
+                fs::copy(JOURNAL_PATH, &journal)?;
+            }
+            Ok(())
+        },
+    )
+}
+
+// This function runs the chroot stage inside the new system through arch-chroot, with the plan and the answers given up front written to /install.toml so it doesn't ask anything. The file holds the passwords, so only root can read it and it's removed once the stage is done. The inner stage keeps its own journal in the new system and always resumes it, so running this step again continues where the chroot stage stopped.
+pub fn zfs_continue_into_chroot(
+    plan: &Plan,
+    answers: &Plan,
+    runner: &mut dyn Runner,
+) -> Result<()> {
+    let answers_file = plan.in_target("/install.toml");
+    let commands = vec![
+        Cmd::new("arch-chroot").args([
+            plan.stages.target.as_str(),
+            plan.stages.installer.as_str(),
+            "--plan",
+            "/install.toml",
+            "--resume",
+            "chroot",
+        ]),
+        Cmd::new("rm").arg(&answers_file),
+    ];
+
+    // The answers are written again on every run until the chroot stage completed, a resumed run may have new passwords or a fixed plan
+    if !runner.step_done("zfs_continue_into_chroot/0", &commands[0]) {
+        runner.perform_always(
+            "zfs_continue_into_chroot/answers",
+            &format!(
+                "Write the plan with the answers for the chroot stage to {}",
+                answers_file
+            ),
+            &mut || {
+                let contents = toml::to_string(answers)
+                    .map_err(|err| InstallError::InvalidPlan(err.to_string()))?;
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&answers_file)?
+                    .write_all(contents.as_bytes())?;
+                Ok(())
+            },
+        )?;
+    }
+
+    execute_steps(runner, "zfs_continue_into_chroot", &commands)
 }
 
-// This function lists all available drives in the /dev/disk/by-id directory, and prompts the user to select one. It returns the selected drive as a String.
-pub fn zfs_select_drive() -> Result<String, std::io::Error> {
-    let devices_dir = "/dev/disk/by-id";
+// This function creates the sparse disk image from the plan, or reuses the one already there, and attaches it as a loop device with its partitions scanned unless an earlier run left it attached. It returns the path of the loop device, which stands in for a drive for the rest of the stage.
+pub fn zfs_attach_image(plan: &Plan, runner: &mut dyn Runner, image: &str) -> Result<String> {
+    execute_steps(
+        runner,
+        "zfs_attach_image",
+        &[Cmd::new("truncate").args(["-s", plan.disk.image_size.as_str(), image])],
+    )?;
 
-    // Get a list of devices in the directory
-    let entries = fs::read_dir(devices_dir)?;
+    // An image still attached from an earlier run is reused, attaching it a second time would give the same pool and partitions another device. After a reboot it isn't attached anymore and gets a new loop device
+    let attached = runner.execute(&Cmd::new("losetup").args(["-j", image]))?;
+    if let Some((device, _)) = attached
+        .stdout
+        .lines()
+        .next()
+        .and_then(|line| line.split_once(':'))
+    {
+        println!("{} is still attached as {}, reusing it", image, device);
+        return Ok(device.to_string());
+    }
 
-    let mut devices = Vec::new();
-    // Iterate through the directory entries and collect device names that do not contain "part"
-    for entry in entries {
-        let path = entry?.path();
-        if let Some(name) = path.file_name() {
-            if let Some(name_str) = name.to_str() {
-                if !name_str.contains("part") {
-                    devices.push(name_str.to_owned());
+    let output =
+        runner.execute(&Cmd::new("losetup").args(["--find", "--show", "--partscan", image]))?;
+    let device = output.stdout.trim();
+    if device.is_empty() && runner.dry_run() {
+        return Ok("/dev/loop0".to_string());
+    }
+
+    Ok(device.to_string())
+}
+
+// This function unmounts everything the stage mounted from the disk image, exports the pool so it is clean when the image boots, and detaches the loop device. When the stage continued into the finish stage that already unmounted and exported everything.
+pub fn zfs_detach_image(plan: &Plan, runner: &mut dyn Runner, device: &str) -> Result<()> {
+    let mut commands = Vec::new();
+    if !plan.stages.finish {
+        commands.push(Cmd::new("umount").arg(plan.in_target("/boot")));
+        if plan.partitions.number(PartitionKind::Xbootldr).is_some() {
+            commands.push(Cmd::new("umount").arg(plan.in_target("/efi")));
+        }
+        commands.push(Cmd::new("zpool").arg("export").arg(&plan.pool.name)); // Exporting unmounts all the datasets
+    }
+    commands.push(Cmd::new("losetup").args(["-d", device]));
+
+    execute_steps(runner, "zfs_detach_image", &commands)
+}
+
+// This function shows everything that is on the drives about to be wiped and makes the user type the serial of every drive (or its kernel name when it has no serial) to go ahead, so a mistyped number can't wipe the wrong drive. With --yes the list is only shown, a dry run never asks since nothing is wiped.
+pub fn zfs_confirm_wipe(runner: &dyn Runner, drives: &[String], yes: bool) -> Result<()> {
+    println!("The following drives will be wiped, everything on them will be lost:");
+
+    let mut confirmations = Vec::new();
+    for drive in drives {
+        match disks::find(drive)? {
+            Some(disk) => {
+                println!("  {}", disk.describe());
+                for partition in &disk.partitions {
+                    println!(
+                        "      {} {} {} {}",
+                        partition.name,
+                        partition.fs_type.as_deref().unwrap_or("unknown"),
+                        partition.label.as_deref().unwrap_or("-"),
+                        partition.mountpoint.as_deref().unwrap_or("not mounted")
+                    );
                 }
+                let confirmation = disk.serial.clone().unwrap_or_else(|| disk.name.clone());
+                confirmations.push((drive, confirmation));
+            }
+            None => {
+                println!("  {} (not found)", drive);
+                confirmations.push((drive, drive.clone()));
             }
         }
     }
 
-    // Print the list of available devices and ask the user to select one
+    if yes || runner.dry_run() {
+        return Ok(());
+    }
+
+    for (drive, confirmation) in confirmations {
+        print!("Type {} to wipe {}: ", confirmation, drive);
+        io::stdout().flush()?;
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+
+        if input.trim() != confirmation {
+            return Err(InstallError::InvalidInput(format!(
+                "'{}' doesn't match {}, nothing was wiped",
+                input.trim(),
+                confirmation
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Function to download and install ZFS.
+pub fn zfs_get_zfs(runner: &mut dyn Runner) -> Result<String> {
+    // Download the ZFS installation script with curl and execute it using the bash command.
+    runner.execute_step("zfs_get_zfs/0", &Cmd::shell(SHELL_GET_ZFS))?;
+
+    // Return a success message.
+    Ok("Installed ZFS".to_string())
+}
+
+// This function lists every physical disk with its size, model, serial, type, connection and what's on it, and prompts the user to select one or more of the disks that can be installed to. It returns the /dev/disk/by-id names of the selected drives in the order they were entered.
+pub fn zfs_select_drives(resume: bool) -> Result<Vec<String>> {
+    let disks = disks::discover()?;
+
+    // Split the disks in the ones that can be selected and the ones that can't, like the live boot medium
+    let (devices, unavailable): (Vec<&Disk>, Vec<&Disk>) = disks
+        .iter()
+        .partition(|disk| disk.unavailable_reason(resume).is_none());
+
+    // Print the list of available devices and ask the user to select one or more
     println!("Available drives:");
     for (i, device) in devices.iter().enumerate() {
-        println!("  {}) {}", i + 1, device);
+        println!("  {}) {}", i + 1, device.describe());
+    }
+    for device in unavailable {
+        println!(
+            "  -) {} is not offered because {}",
+            device.describe(),
+            device.unavailable_reason(resume).unwrap_or_default()
+        );
     }
 
-    // Prompt user to select a drive and read their input
-    print!("Enter the number of the drive you want to use: ");
+    if devices.is_empty() {
+        return Err(InstallError::PreconditionFailed(
+            "There are no drives that can be installed to".to_string(),
+        ));
+    }
+
+    // Prompt user to select drives and read their input, numbers can be separated by spaces or commas
+    print!("Enter the numbers of the drives you want to use (for example 1 or 1,2): ");
     io::stdout().flush()?;
     let mut input = String::new();
     io::stdin().read_line(&mut input)?;
-    let index = input
+
+    let mut selected_devices: Vec<String> = Vec::new();
+    // Split the line without its line break, which would otherwise end up in the last number
+    for choice in input
         .trim()
-        .parse::<usize>()
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|choice| !choice.is_empty())
+    {
+        let index = choice
+            .parse::<usize>()
+            .map_err(|_| InstallError::InvalidInput(format!("'{}' is not a number", choice)))?;
+
+        let device = index
+            .checked_sub(1)
+            .and_then(|index| devices.get(index))
+            .and_then(|device| device.id.clone())
+            .ok_or_else(|| InstallError::InvalidInput(format!("There is no drive {}", index)))?;
+
+        if selected_devices.contains(&device) {
+            return Err(InstallError::InvalidInput(format!(
+                "Drive {} was selected twice",
+                index
+            )));
+        }
+        selected_devices.push(device);
+    }
 
-    // Return the selected device
-    let selected_device = devices
-        .get(index - 1)
-        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid selection"))?;
+    if selected_devices.is_empty() {
+        return Err(InstallError::InvalidInput(
+            "No drive was selected".to_string(),
+        ));
+    }
 
-    Ok(selected_device.clone())
+    // Return the selected devices
+    Ok(selected_devices)
 }
 
-// This function partitions the specified drive into two partitions for EFI and ZFS, formats the EFI partition with FAT32, and erases all data on the drive. The function takes the drive's name as input and returns a `String` indicating the completion of the operation.
-pub fn zfs_partition_drive(drive: &str) -> std::io::Result<String> {
-    // Define a vector of commands to execute
+// This function asks how the selected drives should be combined in the pool.
+pub fn zfs_select_topology() -> Result<Topology> {
+    let names: Vec<&str> = Topology::ALL
+        .iter()
+        .map(|topology| topology.name())
+        .collect();
+
+    print!("Choose the pool topology ({}): ", names.join("/"));
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    Topology::parse(input.trim()).ok_or_else(|| {
+        InstallError::InvalidInput(format!("'{}' is not a pool topology", input.trim()))
+    })
+}
+
+// This function prepares the key an encrypted pool is created with and returns the file it is read from, or None when the pool isn't encrypted. A passphrase is asked for and kept in /tmp, which is in memory on the live ISO, until the pool has been imported again.
+pub fn zfs_prepare_key(plan: &Plan, runner: &dyn Runner) -> Result<Option<String>> {
+    if !plan.encryption.enabled {
+        return Ok(None);
+    }
+
+    match plan.encryption.key {
+        KeySource::Keyfile => Ok(plan.encryption.keyfile.clone()),
+        KeySource::Passphrase => {
+            let passphrase =
+                rpassword::prompt_password("Enter the passphrase for the encrypted pool: ")?;
+
+            // ZFS refuses passphrases shorter than 8 characters
+            if passphrase.len() < 8 {
+                return Err(InstallError::InvalidInput(
+                    "The passphrase must be at least 8 characters".to_string(),
+                ));
+            }
+            // A typo in the passphrase would lock the pool for good, so it has to be entered twice
+            if rpassword::prompt_password("Enter the passphrase again: ")? != passphrase {
+                return Err(InstallError::InvalidInput(
+                    "The passphrases don't match".to_string(),
+                ));
+            }
+
+            let key_location = format!("/tmp/{}.key", plan.pool.name);
+            if !runner.dry_run() {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&key_location)?
+                    .write_all(passphrase.as_bytes())?;
+            }
+
+            Ok(Some(key_location))
+        }
+    }
+}
+
+// This function returns the commands that wipe the drive with the strategy from the plan. Discard falls back to zap on drives that can't discard, and secure erase on drives that can't be erased in their firmware (USB drives, frozen or unsupported SATA drives, or when nvme or hdparm is missing). A drive that isn't found, like in a dry run on another machine, is wiped with the strategy as it is.
+pub fn zfs_wipe_commands(plan: &Plan, runner: &mut dyn Runner, drive: &str) -> Result<Vec<Cmd>> {
+    let device = disks::device_path(drive);
+    let disk = disks::find(drive)?;
+
+    let mut strategy = plan.disk.wipe;
+    if let Some(disk) = &disk {
+        let fallback = match strategy {
+            WipeStrategy::Discard if !disk.discard => {
+                Some("it doesn't support discard".to_string())
+            }
+            WipeStrategy::SecureErase => zfs_secure_erase_unsupported(runner, disk, &device)?,
+            _ => None,
+        };
+        if let Some(reason) = fallback {
+            println!("Wiping {} with wipefs instead because {}", drive, reason);
+            strategy = WipeStrategy::Zap;
+        }
+    }
+
+    let nvme = match &disk {
+        Some(disk) => disk.transport == Transport::Nvme,
+        None => drive.starts_with("nvme-"),
+    };
+
+    let commands = match strategy {
+        WipeStrategy::Discard => vec![Cmd::new("blkdiscard").args(["-f", device.as_str()])],
+        // wipefs removes both GPT headers and the protective MBR along with every other signature
+        WipeStrategy::Zap => vec![Cmd::new("wipefs").args(["-a", device.as_str()])],
+        // The backup GPT lives in the last MiB, a drive that isn't found is asked for its size in bytes with blockdev. The offset is in bytes so the true end of a disk that isn't a whole number of MiB is zeroed.
+        WipeStrategy::Zero => {
+            let size = match &disk {
+                Some(disk) => Some(disk.size_bytes),
+                None => runner
+                    .execute(&Cmd::new("blockdev").args(["--getsize64", device.as_str()]))?
+                    .stdout
+                    .trim()
+                    .parse::<u64>()
+                    .ok(),
+            };
+            let seek = match size {
+                Some(size) if size > 0 => size.saturating_sub(1024 * 1024).to_string(),
+                // In a dry run blockdev isn't run, show where the offset goes
+                _ if runner.dry_run() => "<size-1MiB>".to_string(),
+                _ => {
+                    return Err(InstallError::PreconditionFailed(format!(
+                        "Couldn't read the size of {} to zero its last MiB",
+                        device
+                    )))
+                }
+            };
+            let dd = || {
+                Cmd::new("dd")
+                    .arg("if=/dev/zero")
+                    .arg(format!("of={}", device))
+                    .args(["bs=1M", "count=1", "conv=fsync"])
+            };
+            vec![
+                dd(),
+                dd().arg("oflag=seek_bytes").arg(format!("seek={}", seek)),
+            ]
+        }
+        // Secure erase format (--ses=1) erases all user data of the namespace
+        WipeStrategy::SecureErase if nvme => {
+            vec![Cmd::new("nvme").args(["format", "--ses=1", "--force", device.as_str()])]
+        }
+        // The ATA security erase needs a user password to be set first, the erase removes it again
+        WipeStrategy::SecureErase => vec![
+            Cmd::new("hdparm").args([
+                "--user-master",
+                "u",
+                "--security-set-pass",
+                "install",
+                device.as_str(),
+            ]),
+            Cmd::new("hdparm").args([
+                "--user-master",
+                "u",
+                "--security-erase",
+                "install",
+                device.as_str(),
+            ]),
+        ],
+        WipeStrategy::None => Vec::new(),
+    };
+
+    Ok(commands)
+}
+
+// This function checks whether the drive can be erased in its firmware and returns why not when it can't. In a dry run nothing is checked so the secure erase commands are shown.
+fn zfs_secure_erase_unsupported(
+    runner: &mut dyn Runner,
+    disk: &Disk,
+    device: &str,
+) -> Result<Option<String>> {
+    if runner.dry_run() {
+        return Ok(None);
+    }
+
+    match disk.transport {
+        Transport::Nvme => {
+            // A missing tool is a reason to fall back, not a failure of the wipe
+            let output = match runner.run(&Cmd::new("nvme").args(["id-ctrl", device])) {
+                Err(InstallError::MissingTool(_)) => {
+                    return Ok(Some("nvme is not installed".to_string()))
+                }
+                output => output?,
+            };
+            if !output.success() {
+                return Ok(Some("nvme could not identify the controller".to_string()));
+            }
+            Ok(None)
+        }
+        Transport::Sata => {
+            let output = match runner.run(&Cmd::new("hdparm").args(["-I", device])) {
+                Err(InstallError::MissingTool(_)) => {
+                    return Ok(Some("hdparm is not installed".to_string()))
+                }
+                output => output?,
+            };
+            if !output.success() {
+                return Ok(Some(
+                    "hdparm could not read its security features".to_string(),
+                ));
+            }
+
+            // The security section of hdparm -I lists "supported" and "not frozen" on their own lines
+            let mut lines = output.stdout.lines().map(|line| line.trim());
+            if !lines.any(|line| line == "supported") {
+                return Ok(Some(
+                    "it doesn't support the ATA security erase".to_string(),
+                ));
+            }
+            if !output.stdout.contains("not\tfrozen") {
+                return Ok(Some(
+                    "its security is frozen by the firmware, suspending and resuming the system usually unfreezes it"
+                        .to_string(),
+                ));
+            }
+            Ok(None)
+        }
+        transport => Ok(Some(format!(
+            "secure erase isn't available over {}",
+            transport.name()
+        ))),
+    }
+}
+
+// This function partitions the specified drive with the layout from the plan on a new GPT it writes itself: an optional BIOS boot partition, the ESP, an optional XBOOTLDR partition, an optional swap partition and the ZFS partition with the rest of the drive apart from the reserved space. The table is read back to check it, then the kernel is told to read it and once the partitions showed up the ESP and XBOOTLDR partitions are formatted with FAT32 and plain swap gets a swap signature, encrypted swap is set up on every boot instead. The function takes the drive's name as input and returns a `String` indicating the completion of the operation.
+pub fn zfs_partition_drive(plan: &Plan, runner: &mut dyn Runner, drive: &str) -> Result<String> {
+    let partitions = &plan.partitions;
+    let device = disks::device_path(drive);
+    // A disk image is known by its path in the steps, the loop device it is attached as can change between runs
+    let (name, shown) = match &plan.disk.image {
+        Some(image) => (image.as_str(), image.as_str()),
+        None => (drive, device.as_str()),
+    };
+
+    // The type, name and size of every partition, the ZFS partition has no size since it takes the rest of the drive
+    let layout: Vec<(&str, &str, Option<u64>)> = partitions
+        .layout()
+        .into_iter()
+        .map(|kind| match kind {
+            PartitionKind::BiosBoot => (gpt::BIOS_BOOT, "BIOS", Some(1 << 20)),
+            PartitionKind::Esp => (gpt::EFI_SYSTEM, "EFI", size_bytes(&partitions.esp_size)),
+            PartitionKind::Xbootldr => (
+                gpt::XBOOTLDR,
+                "XBOOTLDR",
+                partitions.xbootldr_size.as_deref().and_then(size_bytes),
+            ),
+            PartitionKind::Swap => (
+                gpt::LINUX_SWAP,
+                "swap",
+                partitions.swap_size.as_deref().and_then(size_bytes),
+            ),
+            PartitionKind::Zfs => (gpt::SOLARIS_ZFS, "ZFS", None),
+        })
+        .collect();
+    let reserved = partitions
+        .reserved
+        .as_deref()
+        .and_then(size_bytes)
+        .unwrap_or(0);
+
+    let description: Vec<String> = layout
+        .iter()
+        .enumerate()
+        .map(|(index, (_, name, size))| match size {
+            Some(size) => format!("{} {} {}", index + 1, name, disks::format_size(*size)),
+            None => format!(
+                "{} {} rest, {} reserved",
+                index + 1,
+                name,
+                disks::format_size(reserved)
+            ),
+        })
+        .collect();
+
+    runner.perform_step(
+        &format!("zfs_partition_drive/{}/gpt", name),
+        &format!("Write a GPT to {}: {}", shown, description.join(", ")),
+        &mut || {
+            let sector_size =
+                disks::find(drive)?.map_or(512, |disk| disk.logical_sector_size as u64);
+            let mut file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&device)?;
+            let disk_bytes = file.seek(SeekFrom::End(0))?;
+
+            let mut table = Gpt::new(sector_size, disk_bytes)?;
+            for (type_guid, name, size) in &layout {
+                table.add(type_guid, name, *size, reserved)?;
+            }
+            table.write(&mut file)?;
+
+            // Read the table back so a drive that silently dropped the writes is caught before the pool is created on it
+            if Gpt::read(&mut file, sector_size)? != table {
+                return Err(InstallError::Io(io::Error::other(format!(
+                    "The partition table read back from {} differs from the one written",
+                    device
+                ))));
+            }
+            Ok(())
+        },
+    )?;
+
+    // Have the kernel read the new table and wait for udev to create the device nodes and /dev/disk/by-id links of the partitions
     let commands = vec![
-        format!("blkdiscard -f /dev/disk/by-id/{}", drive), // Erase all data on the drive
-        format!(
-            "sgdisk -n 1:0:+512M -t 1:EF00 -c 1:EFI /dev/disk/by-id/{}",
-            drive
-        ), // Create a 512MB partition for EFI
-        format!(
-            "sgdisk -n 2:0:0 -t 2:BF01 -c 2:ZFS /dev/disk/by-id/{}",
-            drive
-        ), // Create a partition for ZFS
-        format!("mkfs.vfat -F32 /dev/disk/by-id/{}-part1", drive), // Format the EFI partition with FAT32
+        Cmd::new("blockdev").args(["--rereadpt", device.as_str()]),
+        Cmd::new("udevadm").arg("settle"),
     ];
+    execute_steps(runner, &format!("zfs_partition_drive/{}", name), &commands)?;
+    if !runner.dry_run() {
+        let partition_paths: Vec<String> = (1..=partitions.layout().len())
+            .map(|number| disks::partition_path(drive, number))
+            .collect();
+        disks::wait_for_devices(&partition_paths)?;
+    }
 
-    // Iterate through the vector of commands and execute them sequentially
-    for command in commands {
-        execute_command(&command)?;
+    let mut commands = Vec::new();
+    for (index, kind) in partitions.layout().into_iter().enumerate() {
+        let partition = disks::partition_path(drive, index + 1);
+        match kind {
+            PartitionKind::Esp | PartitionKind::Xbootldr => {
+                commands.push(Cmd::new("mkfs.vfat").args(["-F32", partition.as_str()]))
+            }
+            PartitionKind::Swap if !partitions.encrypt_swap => {
+                commands.push(Cmd::new("mkswap").arg(partition))
+            }
+            _ => {}
+        }
     }
 
+    // Execute the commands sequentially as steps, the drive is part of the section so every drive has its own steps
+    execute_steps(runner, &format!("zfs_format_drive/{}", name), &commands)?;
+
     // Return a message indicating that the disk has been formatted
-    Ok(format!("Disk Formatted"))
+    Ok("Disk Formatted".to_string())
 }
 
-// This function creates a ZFS filesystem on the specified drive by executing a sequence of shell commands using `Command` from the standard library. The commands create a zpool, set its properties, create several ZFS datasets, unmount all ZFS datasets, export the zpool, import it into the specified directory, mount the default ZFS dataset, create a boot directory, mount the EFI partition to the boot directory, and create an /etc directory. The function takes the drive's name as input and returns a `String` indicating the completion of the operation.
-pub fn zfs_setup_filesystem(drive: &str) -> std::io::Result<String> {
-    // Define a vector of commands to execute
-    let commands = vec![
-        format!("zpool create -f -o ashift=12 -O canmount=off -O acltype=posixacl -O compression=on -O atime=off -O xattr=sa zroot /dev/disk/by-id/{}-part2", drive), // Create a zpool and set its properties
-        "zfs create -o canmount=off -o mountpoint=none zroot/ROOT".to_string(), // Create the ZFS datasets
-        "zfs create -o canmount=noauto -o mountpoint=/ zroot/ROOT/default".to_string(),
-        "zfs create -o mountpoint=none zroot/data".to_string(),
-        "zfs create -o mountpoint=/home zroot/data/home".to_string(),
-        "zfs umount -a".to_string(), // Unmount all ZFS datasets
-        "zpool export zroot".to_string(), // Export the zpool
-        "zpool import -d /dev/disk/by-id -R /mnt zroot".to_string(), // Import the zpool into the specified directory
-        "zfs mount zroot/ROOT/default".to_string(), // Mount the default ZFS dataset
-        "zpool set bootfs=zroot/ROOT/default zroot".to_string(), // Set the bootfs property of the zpool
-        "mkdir /mnt/boot".to_string(), // Create a boot directory
-        format!("mount /dev/disk/by-id/{}-part1 /mnt/boot", drive), // Mount the EFI partition to the boot directory
-        "mkdir /mnt/etc".to_string(), // Create an /etc directory
-    ];
+// This function creates a ZFS filesystem on the specified drives by executing a sequence of commands through the runner. The commands create a zpool with the ZFS partitions of the drives combined by the topology, set its properties, create the datasets from the plan, unmount all ZFS datasets, export the zpool, import it into the specified directory, mount the root ZFS dataset, create a boot directory, mount the EFI partition of the first drive to the boot directory, and create an /etc directory. The function returns a `String` indicating the completion of the operation.
+pub fn zfs_setup_filesystem(
+    plan: &Plan,
+    runner: &mut dyn Runner,
+    drives: &[String],
+    topology: Topology,
+    key_location: Option<&str>,
+) -> Result<String> {
+    let pool = &plan.pool.name;
+    let root_dataset = plan.root_dataset();
+
+    // An encrypted pool is created and imported with the key from the file, every dataset inherits the encryption from the pool
+    let (encryption, load_key) = match key_location {
+        Some(key_location) => {
+            let keyformat = match plan.encryption.key {
+                KeySource::Passphrase => "passphrase",
+                KeySource::Keyfile => "raw",
+            };
+            (
+                vec![
+                    "-O".to_string(),
+                    "encryption=aes-256-gcm".to_string(),
+                    "-O".to_string(),
+                    format!("keyformat={}", keyformat),
+                    "-O".to_string(),
+                    format!("keylocation=file://{}", key_location),
+                ],
+                Some("-l"),
+            )
+        }
+        None => (Vec::new(), None),
+    };
+
+    // The vdev is the topology followed by the ZFS partition of every drive
+    let zfs_partition = plan
+        .partitions
+        .number(PartitionKind::Zfs)
+        .unwrap_or_default();
+    let mut vdev: Vec<String> = topology.vdev().map(str::to_string).into_iter().collect();
+    vdev.extend(
+        drives
+            .iter()
+            .map(|drive| disks::partition_path(drive, zfs_partition)),
+    );
+
+    // The pool and filesystem properties from the plan, the root of the pool never mounts itself
+    let mut properties: Vec<String> = Vec::new();
+    for (property, value) in plan.pool_properties(zfs_detect_ashift(drives)) {
+        properties.extend(["-o".to_string(), format!("{}={}", property, value)]);
+    }
+    properties.extend(["-O".to_string(), "canmount=off".to_string()]);
+    for (property, value) in plan.filesystem_properties() {
+        properties.extend(["-O".to_string(), format!("{}={}", property, value)]);
+    }
+
+    // Create a zpool and set its properties, with the target as its root so new datasets never mount over the live system
+    let mut commands = vec![Cmd::new("zpool")
+        .args(["create", "-f", "-R", plan.stages.target.as_str()])
+        .args(properties)
+        .args(encryption)
+        .arg(pool)
+        .args(vdev)];
+
+    // Create the ZFS datasets from the plan, parents before their children
+    for dataset in plan.datasets_in_order() {
+        let mut command = Cmd::new("zfs").arg("create");
+        if let Some(canmount) = &dataset.canmount {
+            command = command.arg("-o").arg(format!("canmount={}", canmount));
+        }
+        if let Some(mountpoint) = &dataset.mountpoint {
+            command = command.arg("-o").arg(format!("mountpoint={}", mountpoint));
+        }
+        for (property, value) in &dataset.properties {
+            command = command.arg("-o").arg(format!("{}={}", property, value));
+        }
+        commands.push(command.arg(format!("{}/{}", pool, dataset.name)));
+    }
+
+    // The pool is imported by the names in /dev/disk/by-id so it doesn't depend on the order the kernel finds the drives in, a loop device has no such name so its partition is given directly
+    let search: Vec<String> = if plan.disk.image.is_some() {
+        drives
+            .iter()
+            .flat_map(|drive| {
+                [
+                    "-d".to_string(),
+                    disks::partition_path(drive, zfs_partition),
+                ]
+            })
+            .collect()
+    } else {
+        vec!["-d".to_string(), "/dev/disk/by-id".to_string()]
+    };
 
-    // Iterate through the vector of commands and execute them sequentially
-    for command in commands {
-        execute_command(&command)?;
+    commands.extend(vec![
+        Cmd::new("zfs").args(["umount", "-a"]), // Unmount all ZFS datasets
+        Cmd::new("zpool").arg("export").arg(pool), // Export the zpool
+        Cmd::new("zpool")
+            .args(["import", "-N"])
+            .args(load_key)
+            .args(search)
+            .args(["-R", plan.stages.target.as_str(), pool.as_str()]), // Import the zpool into the specified directory without mounting anything, loading the key when it's encrypted
+        Cmd::new("zfs").arg("mount").arg(&root_dataset), // Mount the root ZFS dataset first so nothing is hidden underneath it
+        Cmd::new("zfs").args(["mount", "-a"]), // Mount the other datasets, zfs mounts parents before children
+        Cmd::new("zpool")
+            .arg("set")
+            .arg(format!("bootfs={}", root_dataset))
+            .arg(pool), // Set the bootfs property of the zpool
+    ]);
+
+    // Mount the EFI partition of the first drive, and its XBOOTLDR partition on /boot when there is one
+    let esp_mountpoint = plan.in_target(plan.partitions.esp_mountpoint());
+    commands.extend(vec![
+        Cmd::new("mkdir").arg(&esp_mountpoint),
+        Cmd::new("mount")
+            .arg(disks::partition_path(
+                &drives[0],
+                plan.partitions
+                    .number(PartitionKind::Esp)
+                    .unwrap_or_default(),
+            ))
+            .arg(&esp_mountpoint),
+    ]);
+    if let Some(number) = plan.partitions.number(PartitionKind::Xbootldr) {
+        commands.extend(vec![
+            Cmd::new("mkdir").arg(plan.in_target("/boot")),
+            Cmd::new("mount")
+                .arg(disks::partition_path(&drives[0], number))
+                .arg(plan.in_target("/boot")),
+        ]);
     }
+    commands.push(Cmd::new("mkdir").arg(plan.in_target("/etc"))); // Create an /etc directory
+
+    // Point the pool at where the installed system gets its key from: the initramfs asks for a passphrase, a keyfile is copied into the system
+    if let Some(key_location) = key_location {
+        match plan.encryption.key {
+            KeySource::Passphrase => commands.extend(vec![
+                Cmd::new("zfs").args(["set", "keylocation=prompt", pool.as_str()]),
+                Cmd::new("rm").arg(key_location),
+            ]),
+            KeySource::Keyfile => commands.extend(vec![
+                Cmd::new("mkdir").arg("-p").arg(plan.in_target("/etc/zfs")),
+                Cmd::new("install")
+                    .args(["-m", "000", key_location])
+                    .arg(plan.in_target(&plan.installed_keyfile())),
+                Cmd::new("zfs")
+                    .arg("set")
+                    .arg(format!("keylocation=file://{}", plan.installed_keyfile()))
+                    .arg(pool),
+            ]),
+        }
+    }
+
+    // Execute the commands sequentially as steps
+    execute_steps(runner, "zfs_setup_filesystem", &commands)?;
 
     // Return a message indicating that the ZFS filesystem has been set up
-    Ok(format!("Setup ZFS Filesystem"))
+    Ok("Setup ZFS Filesystem".to_string())
+}
+
+// This function picks the ashift for the pool from the largest physical sector size of the drives, never going below 4K sectors (ashift 12) since many drives report 512 bytes while using 4K internally.
+pub fn zfs_detect_ashift(drives: &[String]) -> u32 {
+    let sector_size = drives
+        .iter()
+        .filter_map(|drive| disks::find(drive).ok().flatten())
+        .map(|disk| disk.physical_sector_size)
+        .max()
+        .unwrap_or(4096);
+
+    sector_size.max(4096).ilog2()
 }
 
-// This function sets up a base system on the ZFS filesystem by executing a sequence of shell commands using `Command` from the standard library. The commands generate the fstab file, install packages, and copy the installation script to the ZFS filesystem. The function takes no input and returns a `String` indicating the completion of the operation.
-fn zfs_setup_basesystem() -> std::io::Result<String> {
+// This function sets up a base system on the ZFS filesystem by executing a sequence of commands through the runner. The commands generate the fstab file, install packages, and add the swap partitions of the drives. The function takes the plan for the package list and returns a `String` indicating the completion of the operation.
+fn zfs_setup_basesystem(plan: &Plan, runner: &mut dyn Runner, drives: &[String]) -> Result<String> {
+    let target = plan.stages.target.as_str();
+    let fstab = plan.in_target("/etc/fstab");
+
+    // genfstab writes the fstab to its stdout, from where it is added to the fstab in the target
+    let generated = runner
+        .execute(&Cmd::new("genfstab").args(["-U", target]))?
+        .stdout;
+
     // Define a vector of commands to execute
-    let commands = vec![
-        "genfstab -U /mnt >> /mnt/etc/fstab".to_string(), // Generate the fstab file
-        "pacstrap /mnt base base-devel linux linux-firmware neovim networkmanager".to_string(), // Install packages
-        "cp install /mnt/install".to_string(), // Copy the installation script to the ZFS filesystem
+    let mut commands = vec![
+        Cmd::new("tee")
+            .args(["-a", fstab.as_str()])
+            .stdin(generated), // Generate the fstab file
+        Cmd::new("pacstrap").arg(target).args(&plan.packages.base), // Install packages
     ];
 
-    // Iterate through the vector of commands and execute them sequentially
-    for command in commands {
-        execute_command(&command)?;
+    // The swap partitions aren't active so genfstab leaves them out. Encrypted swap gets a new random key on every boot from crypttab, which is why it's only added once pacstrap has installed the crypttab from systemd.
+    if let Some(number) = plan.partitions.number(PartitionKind::Swap) {
+        for (index, drive) in drives.iter().enumerate() {
+            // A loop device is gone once the image boots, there the only swap partition is found by its label
+            let partition = if plan.disk.image.is_some() {
+                "PARTLABEL=swap".to_string()
+            } else {
+                disks::partition_path(drive, number)
+            };
+            if plan.partitions.encrypt_swap {
+                commands.extend(vec![
+                    Cmd::new("tee")
+                        .arg("-a")
+                        .arg(plan.in_target("/etc/crypttab"))
+                        .stdin(format!(
+                            "swap{} {} /dev/urandom swap,cipher=aes-xts-plain64,size=512\n",
+                            index, partition
+                        )),
+                    Cmd::new("tee").args(["-a", fstab.as_str()]).stdin(format!(
+                        "/dev/mapper/swap{} none swap defaults 0 0\n",
+                        index
+                    )),
+                ]);
+            } else {
+                commands.push(
+                    Cmd::new("tee")
+                        .args(["-a", fstab.as_str()])
+                        .stdin(format!("{} none swap defaults 0 0\n", partition)),
+                );
+            }
+        }
     }
 
+    // Execute the commands sequentially as steps
+    execute_steps(runner, "zfs_setup_basesystem", &commands)?;
+
     // Return a message indicating that the base system setup is complete
-    Ok(format!("Setup basesystem done"))
+    Ok("Setup basesystem done".to_string())
 }
 
-pub fn execute_command(command: &str) -> std::io::Result<()> {
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(&command)
-        .output()
-        .expect("Failed to execute command");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::RecordingRunner;
 
-    if !output.status.success() {
-        eprintln!(
-            "Command '{}' failed with exit status: {:?}",
-            command, output.status
+    #[test]
+    fn setup_filesystem_runs_the_default_layout() {
+        let plan = Plan::default();
+        let mut runner = RecordingRunner::new();
+        zfs_setup_filesystem(
+            &plan,
+            &mut runner,
+            &["ata-DISK_A".to_string()],
+            Topology::Stripe,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner.commands,
+            [
+                "zpool create -f -R /mnt -o ashift=12 -O canmount=off -O acltype=posixacl -O atime=off -O compression=on -O xattr=sa zroot /dev/disk/by-id/ata-DISK_A-part2",
+                "zfs create -o canmount=off -o mountpoint=none zroot/ROOT",
+                "zfs create -o mountpoint=none zroot/data",
+                "zfs create -o canmount=noauto -o mountpoint=/ zroot/ROOT/default",
+                "zfs create -o mountpoint=/home zroot/data/home",
+                "zfs umount -a",
+                "zpool export zroot",
+                "zpool import -N -d /dev/disk/by-id -R /mnt zroot",
+                "zfs mount zroot/ROOT/default",
+                "zfs mount -a",
+                "zpool set bootfs=zroot/ROOT/default zroot",
+                "mkdir /mnt/boot",
+                "mount /dev/disk/by-id/ata-DISK_A-part1 /mnt/boot",
+                "mkdir /mnt/etc",
+            ]
         );
-        std::process::exit(1);
     }
 
-    println!("{}", String::from_utf8_lossy(&output.stdout));
+    #[test]
+    fn setup_filesystem_creates_an_encrypted_mirror_with_a_keyfile() {
+        let plan = Plan::parse(
+            r#"
+            [encryption]
+            enabled = true
+            key = "keyfile"
+            keyfile = "/root/zroot.key"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+        let drives = ["ata-DISK_A".to_string(), "ata-DISK_B".to_string()];
+        zfs_setup_filesystem(
+            &plan,
+            &mut runner,
+            &drives,
+            Topology::Mirror,
+            Some("/root/zroot.key"),
+        )
+        .unwrap();
+
+        assert!(runner.commands[0].ends_with(
+            "-O encryption=aes-256-gcm -O keyformat=raw -O keylocation=file:///root/zroot.key zroot mirror /dev/disk/by-id/ata-DISK_A-part2 /dev/disk/by-id/ata-DISK_B-part2"
+        ));
+        assert!(runner
+            .commands
+            .contains(&"zpool import -N -l -d /dev/disk/by-id -R /mnt zroot".to_string()));
+        assert_eq!(
+            runner.commands[runner.commands.len() - 3..],
+            [
+                "mkdir -p /mnt/etc/zfs",
+                "install -m 000 /root/zroot.key /mnt/etc/zfs/zroot.key",
+                "zfs set keylocation=file:///etc/zfs/zroot.key zroot",
+            ]
+        );
+    }
 
-    thread::sleep(Duration::from_secs(3));
+    #[test]
+    fn setup_filesystem_stops_at_the_first_failed_command() {
+        let plan = Plan::default();
+        let mut runner = RecordingRunner::new();
+        runner.fail("zpool create");
+        let result = zfs_setup_filesystem(
+            &plan,
+            &mut runner,
+            &["ata-DISK_A".to_string()],
+            Topology::Stripe,
+            None,
+        );
 
-    Ok(())
+        assert!(matches!(result, Err(InstallError::CommandFailed { .. })));
+        assert_eq!(runner.commands.len(), 1);
+    }
+
+    #[test]
+    fn zero_wipe_seeks_to_the_last_mib_in_bytes() {
+        let plan = Plan::parse(
+            r#"
+            [disk]
+            wipe = "zero"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+        runner.respond("blockdev", "1000204886016\n");
+
+        let commands = zfs_wipe_commands(&plan, &mut runner, "ata-MISSING_DISK").unwrap();
+        let commands: Vec<String> = commands.iter().map(|command| command.to_string()).collect();
+        assert_eq!(
+            commands,
+            [
+                "dd if=/dev/zero of=/dev/disk/by-id/ata-MISSING_DISK bs=1M count=1 conv=fsync",
+                "dd if=/dev/zero of=/dev/disk/by-id/ata-MISSING_DISK bs=1M count=1 conv=fsync oflag=seek_bytes seek=1000203837440",
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_wipe_shows_a_placeholder_for_an_unknown_size() {
+        let plan = Plan::parse(
+            r#"
+            [disk]
+            wipe = "zero"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+
+        let commands = zfs_wipe_commands(&plan, &mut runner, "ata-MISSING_DISK").unwrap();
+        let last = commands[1].to_string();
+        assert!(
+            last.ends_with("oflag=seek_bytes 'seek=<size-1MiB>'"),
+            "{}",
+            last
+        );
+    }
+
+    #[test]
+    fn attach_image_reuses_the_loop_device_of_an_earlier_run() {
+        let plan = Plan::parse(
+            r#"
+            [disk]
+            image = "/var/tmp/arch.img"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+        runner.respond("losetup -j", "/dev/loop3: []: (/var/tmp/arch.img)\n");
+
+        let device = zfs_attach_image(&plan, &mut runner, "/var/tmp/arch.img").unwrap();
+        assert_eq!(device, "/dev/loop3");
+        assert_eq!(
+            runner.commands,
+            [
+                "truncate -s 32G /var/tmp/arch.img",
+                "losetup -j /var/tmp/arch.img",
+            ]
+        );
+    }
+
+    #[test]
+    fn partition_steps_of_an_image_are_named_after_the_image() {
+        let plan = Plan::parse(
+            r#"
+            [disk]
+            image = "/var/tmp/arch.img"
+            "#,
+        )
+        .unwrap();
+        let mut runner = RecordingRunner::new();
+        zfs_partition_drive(&plan, &mut runner, "/dev/loop3").unwrap();
+
+        assert!(
+            runner.commands[0].starts_with("Write a GPT to /var/tmp/arch.img: "),
+            "{}",
+            runner.commands[0]
+        );
+        assert_eq!(runner.commands[1], "blockdev --rereadpt /dev/loop3");
+    }
 }